@@ -0,0 +1,985 @@
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::Path;
+
+use crate::blip::BlipBuffer;
+use crate::gameboy::{CPU_CLOCK_HZ, M_CYCLE_CLOCK};
+use crate::scope::{Oscilloscope, SCOPE_INTERVAL};
+use crate::vgm::VgmWriter;
+use crate::wav::WavWriter;
+
+//出力サンプルレートの既定値
+pub const SAMPLE_RATE: u32 = 48000;
+//この間隔(マシンサイクル)ごとに合成したサンプルを取り出す
+const BLIP_FRAME: u32 = 1024;
+//出力のコンデンサによるハイパスフィルタ 1Tサイクルごとにこの割合だけ電荷が残る(DMG)
+const HPF_CHARGE_PER_CLOCK: f64 = 0.999958;
+
+//NR52
+const APU_ENABLE: u8 = 1 << 7;
+//NRx4
+const TRIGGER: u8 = 1 << 7;
+const LENGTH_ENABLE: u8 = 1 << 6;
+
+//0xFF10~0xFF2Fを読んだときに1になるbit 書き込み専用と未使用のbit
+const READ_MASKS: [u8; 0x20] = [
+    0x80, 0x3F, 0x00, 0xFF, 0xBF, //NR10~NR14
+    0xFF, 0x3F, 0x00, 0xFF, 0xBF, //~, NR21~NR24
+    0x7F, 0xFF, 0x9F, 0xFF, 0xBF, //NR30~NR34
+    0xFF, 0xFF, 0x00, 0x00, 0xBF, //~, NR41~NR44
+    0x00, 0x00, 0x70, //NR50~NR52
+    0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF,
+];
+
+//矩形波のデューティ比 12.5%, 25%, 50%, 75%
+const DUTY_TABLE: [[u8; 8]; 4] = [
+    [0, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 0, 0, 1],
+    [1, 0, 0, 0, 0, 1, 1, 1],
+    [0, 1, 1, 1, 1, 1, 1, 0],
+];
+
+const NOISE_DIVISORS: [u32; 8] = [8, 16, 32, 48, 64, 80, 96, 112];
+
+//長さカウンタ 0になったらチャンネルを止める
+#[derive(Default)]
+struct Length {
+    counter: u16,
+    enabled: bool,
+}
+impl Length {
+    //止まったらfalse
+    fn clock(&mut self) -> bool {
+        if self.enabled && self.counter > 0 {
+            self.counter -= 1;
+            return self.counter > 0;
+        }
+        true
+    }
+}
+
+//音量エンベロープ
+#[derive(Default)]
+struct Envelope {
+    initial: u8,
+    increase: bool,
+    period: u8,
+    volume: u8,
+    timer: u8,
+}
+impl Envelope {
+    fn write(&mut self, val: u8) {
+        self.initial = val >> 4;
+        self.increase = val & 0x08 > 0;
+        self.period = val & 0x07;
+    }
+
+    fn trigger(&mut self) {
+        self.volume = self.initial;
+        self.timer = if self.period == 0 { 8 } else { self.period };
+    }
+
+    fn clock(&mut self) {
+        if self.period == 0 {
+            return;
+        }
+        self.timer = self.timer.saturating_sub(1);
+        if self.timer > 0 {
+            return;
+        }
+        self.timer = self.period;
+        if self.increase && self.volume < 15 {
+            self.volume += 1;
+        } else if !self.increase && self.volume > 0 {
+            self.volume -= 1;
+        }
+    }
+}
+
+//チャンネル1の周波数スイープ
+#[derive(Default)]
+struct Sweep {
+    period: u8,
+    negate: bool,
+    shift: u8,
+    timer: u8,
+    enabled: bool,
+    shadow: u16,
+    negate_used: bool, //減算で計算した後にnegateを戻すとチャンネルが止まる
+}
+impl Sweep {
+    fn write(&mut self, val: u8) -> bool {
+        self.period = (val >> 4) & 0x07;
+        self.negate = val & 0x08 > 0;
+        self.shift = val & 0x07;
+        !self.negate_used || self.negate
+    }
+
+    fn calculate(&mut self) -> u16 {
+        let delta = self.shadow >> self.shift;
+        if self.negate {
+            self.negate_used = true;
+            self.shadow.wrapping_sub(delta)
+        } else {
+            self.shadow + delta
+        }
+    }
+}
+
+struct Pulse {
+    enabled: bool,
+    dac: bool,
+    duty: u8,
+    duty_pos: u8,
+    freq: u16,
+    timer: u32,
+    length: Length,
+    envelope: Envelope,
+}
+impl Pulse {
+    fn new() -> Self {
+        Self {
+            enabled: false,
+            dac: false,
+            duty: 0,
+            duty_pos: 0,
+            freq: 0,
+            timer: 0,
+            length: Length::default(),
+            envelope: Envelope::default(),
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.freq as u32) * 4
+    }
+
+    fn tick(&mut self, mut cycles: u32) {
+        while cycles > 0 {
+            if self.timer > cycles {
+                self.timer -= cycles;
+                return;
+            }
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.duty_pos = (self.duty_pos + 1) & 7;
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac;
+        self.timer = self.period();
+        self.envelope.trigger();
+    }
+
+    fn output(&self) -> u8 {
+        DUTY_TABLE[self.duty as usize][self.duty_pos as usize] * self.envelope.volume
+    }
+}
+
+struct Wave {
+    enabled: bool,
+    dac: bool,
+    volume_code: u8,
+    freq: u16,
+    timer: u32,
+    position: u8,
+    sample: u8, //最後に読んだ4bitのサンプル
+    length: Length,
+}
+impl Wave {
+    fn new() -> Self {
+        Self {
+            enabled: false,
+            dac: false,
+            volume_code: 0,
+            freq: 0,
+            timer: 0,
+            position: 0,
+            sample: 0,
+            length: Length::default(),
+        }
+    }
+
+    fn period(&self) -> u32 {
+        (2048 - self.freq as u32) * 2
+    }
+
+    fn tick(&mut self, mut cycles: u32, wave_ram: &[u8; 0x10]) {
+        while cycles > 0 {
+            if self.timer > cycles {
+                self.timer -= cycles;
+                return;
+            }
+            cycles -= self.timer;
+            self.timer = self.period();
+            self.position = (self.position + 1) & 31;
+            let byte = wave_ram[(self.position >> 1) as usize];
+            self.sample = if self.position & 1 == 0 { byte >> 4 } else { byte & 0x0F };
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac;
+        //最初のサンプルを読むまで少し遅れる
+        self.timer = self.period() + 6;
+        self.position = 0;
+    }
+
+    fn output(&self) -> u8 {
+        match self.volume_code {
+            0 => 0,
+            code => self.sample >> (code - 1),
+        }
+    }
+}
+
+struct Noise {
+    enabled: bool,
+    dac: bool,
+    shift: u8,
+    width7: bool,
+    divisor: u8,
+    timer: u32,
+    lfsr: u16,
+    length: Length,
+    envelope: Envelope,
+}
+impl Noise {
+    fn new() -> Self {
+        Self {
+            enabled: false,
+            dac: false,
+            shift: 0,
+            width7: false,
+            divisor: 0,
+            timer: 0,
+            lfsr: 0x7FFF,
+            length: Length::default(),
+            envelope: Envelope::default(),
+        }
+    }
+
+    fn period(&self) -> u32 {
+        NOISE_DIVISORS[self.divisor as usize] << self.shift
+    }
+
+    fn tick(&mut self, mut cycles: u32) {
+        while cycles > 0 {
+            if self.timer > cycles {
+                self.timer -= cycles;
+                return;
+            }
+            cycles -= self.timer;
+            self.timer = self.period();
+            let bit = (self.lfsr ^ (self.lfsr >> 1)) & 1;
+            self.lfsr = (self.lfsr >> 1) | (bit << 14);
+            if self.width7 {
+                self.lfsr = (self.lfsr & !(1 << 6)) | (bit << 6);
+            }
+        }
+    }
+
+    fn trigger(&mut self) {
+        self.enabled = self.dac;
+        self.timer = self.period();
+        self.lfsr = 0x7FFF;
+        self.envelope.trigger();
+    }
+
+    fn output(&self) -> u8 {
+        (!self.lfsr & 1) as u8 * self.envelope.volume
+    }
+}
+
+//ミュートやソロの指定に使う
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Channel {
+    Pulse1,
+    Pulse2,
+    Wave,
+    Noise,
+}
+impl Channel {
+    pub const ALL: [Channel; 4] = [Self::Pulse1, Self::Pulse2, Self::Wave, Self::Noise];
+}
+
+pub struct Apu {
+    regs: [u8; 0x20], //0xFF10~0xFF2F 読み出し用に書き込まれた値を残す
+    wave_ram: [u8; 0x10], //0xFF30~0xFF3F
+    power: bool,
+    pulse1: Pulse,
+    sweep: Sweep,
+    pulse2: Pulse,
+    wave: Wave,
+    noise: Noise,
+    fs_step: u8, //フレームシーケンサが次に実行するステップ
+    div_bit: bool, //DIVのbit4(内部カウンタのbit12) 立ち下がりでフレームシーケンサが進む
+    sample_rate: u32,
+    blips: [BlipBuffer; 2], //L, R
+    blip_clock: u32, //今のフレームで進んだマシンサイクル
+    last_output: [f32; 2], //最後にBlipBufferに渡した振幅
+    hpf_capacitor: [f32; 2],
+    hpf_charge: f32, //1サンプルあたりの電荷の残る割合
+    samples: Vec<f32>, //L, Rの順
+    muted: [bool; 4],
+    solo: [bool; 4], //どれかがソロならソロのチャンネルだけ鳴らす
+    scope: Oscilloscope,
+    scope_clock: u32,
+    recorder: Option<WavWriter<BufWriter<File>>>, //出力した全サンプルを書き出す
+    record_error: Option<io::Error>, //録音中に起きた最初のエラー 止めたときに返す
+    clock: u64, //電源を入れてからのマシンサイクル VGMの時刻に使う
+    vgm: Option<VgmWriter<BufWriter<File>>>, //レジスタへの書き込みを記録する
+}
+impl Apu {
+    pub fn new() -> Self {
+        Self {
+            regs: [0; 0x20],
+            wave_ram: [0; 0x10],
+            power: false,
+            pulse1: Pulse::new(),
+            sweep: Sweep::default(),
+            pulse2: Pulse::new(),
+            wave: Wave::new(),
+            noise: Noise::new(),
+            fs_step: 0,
+            div_bit: false,
+            sample_rate: SAMPLE_RATE,
+            blips: [Self::blip(SAMPLE_RATE), Self::blip(SAMPLE_RATE)],
+            blip_clock: 0,
+            last_output: [0.0; 2],
+            hpf_capacitor: [0.0; 2],
+            hpf_charge: Self::hpf_charge(SAMPLE_RATE),
+            samples: Vec::new(),
+            muted: [false; 4],
+            solo: [false; 4],
+            scope: Oscilloscope::new(),
+            scope_clock: 0,
+            recorder: None,
+            record_error: None,
+            clock: 0,
+            vgm: None,
+        }
+    }
+
+    fn blip(sample_rate: u32) -> BlipBuffer {
+        BlipBuffer::new((CPU_CLOCK_HZ / M_CYCLE_CLOCK) as f64, sample_rate as f64, BLIP_FRAME)
+    }
+
+    fn hpf_charge(sample_rate: u32) -> f32 {
+        HPF_CHARGE_PER_CLOCK.powf(CPU_CLOCK_HZ as f64 / sample_rate as f64) as f32
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    //出力サンプルレートを変える 溜まっているサンプルは捨てる
+    //録音するときは始める前に変えておくこと(WAVのレートは録音開始時のまま)
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        assert!(sample_rate > 0);
+        self.sample_rate = sample_rate;
+        self.blips = [Self::blip(sample_rate), Self::blip(sample_rate)];
+        self.blip_clock = 0;
+        self.last_output = [0.0; 2];
+        self.hpf_capacitor = [0.0; 2];
+        self.hpf_charge = Self::hpf_charge(sample_rate);
+        self.samples.clear();
+    }
+
+    //誰も取り出さないときに溜め込むサンプル数の上限(ステレオ1秒分)
+    fn max_samples(&self) -> usize {
+        self.sample_rate as usize * 2
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF26 => {
+                (APU_ENABLE * self.power as u8)
+                    | READ_MASKS[0x16]
+                    | (self.pulse1.enabled as u8)
+                    | (self.pulse2.enabled as u8) << 1
+                    | (self.wave.enabled as u8) << 2
+                    | (self.noise.enabled as u8) << 3
+            },
+            0xFF10..=0xFF2F => self.regs[addr as usize - 0xFF10] | READ_MASKS[addr as usize - 0xFF10],
+            //再生中は今読んでいるバイトしか見えない
+            0xFF30..=0xFF3F => if self.wave.enabled {
+                self.wave_ram[(self.wave.position >> 1) as usize]
+            } else {
+                self.wave_ram[addr as usize & 0x0F]
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        if let Some(vgm) = &mut self.vgm {
+            vgm.write_register(self.clock, addr, val);
+        }
+        match addr {
+            0xFF26 => self.write_power(val & APU_ENABLE > 0),
+            0xFF30..=0xFF3F => if self.wave.enabled {
+                self.wave_ram[(self.wave.position >> 1) as usize] = val;
+            } else {
+                self.wave_ram[addr as usize & 0x0F] = val;
+            },
+            0xFF10..=0xFF2F => {
+                //電源オフの間はDMGでは長さカウンタだけ書き込める
+                if !self.power {
+                    match addr {
+                        0xFF11 => self.pulse1.length.counter = 64 - (val & 0x3F) as u16,
+                        0xFF16 => self.pulse2.length.counter = 64 - (val & 0x3F) as u16,
+                        0xFF1B => self.wave.length.counter = 256 - val as u16,
+                        0xFF20 => self.noise.length.counter = 64 - (val & 0x3F) as u16,
+                        _ => {},
+                    }
+                    return;
+                }
+                self.regs[addr as usize - 0xFF10] = val;
+                self.write_register(addr, val);
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    fn write_power(&mut self, on: bool) {
+        if self.power && !on {
+            //電源オフでNR10~NR51は全部0になる 長さカウンタはDMGでは残る
+            for addr in 0xFF10..=0xFF25 {
+                match addr {
+                    0xFF11 => self.pulse1.duty = 0,
+                    0xFF16 => self.pulse2.duty = 0,
+                    0xFF1B | 0xFF20 => {},
+                    _ => self.write_register(addr, 0),
+                }
+            }
+            self.regs = [0; 0x20];
+            self.pulse1.enabled = false;
+            self.pulse2.enabled = false;
+            self.wave.enabled = false;
+            self.noise.enabled = false;
+        } else if !self.power && on {
+            self.fs_step = 0;
+            self.pulse1.duty_pos = 0;
+            self.pulse2.duty_pos = 0;
+            self.wave.sample = 0;
+        }
+        self.power = on;
+    }
+
+    //次のフレームシーケンサのステップで長さカウンタが進まないか
+    fn length_clock_pending(&self) -> bool {
+        self.fs_step & 1 == 1
+    }
+
+    //NRx4の長さカウンタ有効化とトリガーの共通処理
+    //長さカウンタを進めないステップの前に有効にすると一回余分に進む
+    fn write_length_control(length: &mut Length, enabled: &mut bool, val: u8, max: u16, extra_clock: bool) {
+        let was_enabled = length.enabled;
+        length.enabled = val & LENGTH_ENABLE > 0;
+        if extra_clock && !was_enabled && length.enabled && length.counter > 0 {
+            length.counter -= 1;
+            if length.counter == 0 && val & TRIGGER == 0 {
+                *enabled = false;
+            }
+        }
+        if val & TRIGGER > 0 && length.counter == 0 {
+            length.counter = if extra_clock && length.enabled { max - 1 } else { max };
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        let extra_clock = self.length_clock_pending();
+        match addr {
+            0xFF10 => self.pulse1.enabled &= self.sweep.write(val),
+            0xFF11 => {
+                self.pulse1.duty = val >> 6;
+                self.pulse1.length.counter = 64 - (val & 0x3F) as u16;
+            },
+            0xFF12 => {
+                self.pulse1.envelope.write(val);
+                self.pulse1.dac = val & 0xF8 > 0;
+                self.pulse1.enabled &= self.pulse1.dac;
+            },
+            0xFF13 => self.pulse1.freq = (self.pulse1.freq & 0x700) | val as u16,
+            0xFF14 => {
+                self.pulse1.freq = (self.pulse1.freq & 0xFF) | ((val & 0x07) as u16) << 8;
+                Self::write_length_control(&mut self.pulse1.length, &mut self.pulse1.enabled, val, 64, extra_clock);
+                if val & TRIGGER > 0 {
+                    self.pulse1.trigger();
+                    self.trigger_sweep();
+                }
+            },
+            0xFF16 => {
+                self.pulse2.duty = val >> 6;
+                self.pulse2.length.counter = 64 - (val & 0x3F) as u16;
+            },
+            0xFF17 => {
+                self.pulse2.envelope.write(val);
+                self.pulse2.dac = val & 0xF8 > 0;
+                self.pulse2.enabled &= self.pulse2.dac;
+            },
+            0xFF18 => self.pulse2.freq = (self.pulse2.freq & 0x700) | val as u16,
+            0xFF19 => {
+                self.pulse2.freq = (self.pulse2.freq & 0xFF) | ((val & 0x07) as u16) << 8;
+                Self::write_length_control(&mut self.pulse2.length, &mut self.pulse2.enabled, val, 64, extra_clock);
+                if val & TRIGGER > 0 {
+                    self.pulse2.trigger();
+                }
+            },
+            0xFF1A => {
+                self.wave.dac = val & 0x80 > 0;
+                self.wave.enabled &= self.wave.dac;
+            },
+            0xFF1B => self.wave.length.counter = 256 - val as u16,
+            0xFF1C => self.wave.volume_code = (val >> 5) & 0x03,
+            0xFF1D => self.wave.freq = (self.wave.freq & 0x700) | val as u16,
+            0xFF1E => {
+                self.wave.freq = (self.wave.freq & 0xFF) | ((val & 0x07) as u16) << 8;
+                Self::write_length_control(&mut self.wave.length, &mut self.wave.enabled, val, 256, extra_clock);
+                if val & TRIGGER > 0 {
+                    self.wave.trigger();
+                }
+            },
+            0xFF20 => self.noise.length.counter = 64 - (val & 0x3F) as u16,
+            0xFF21 => {
+                self.noise.envelope.write(val);
+                self.noise.dac = val & 0xF8 > 0;
+                self.noise.enabled &= self.noise.dac;
+            },
+            0xFF22 => {
+                self.noise.shift = val >> 4;
+                self.noise.width7 = val & 0x08 > 0;
+                self.noise.divisor = val & 0x07;
+            },
+            0xFF23 => {
+                Self::write_length_control(&mut self.noise.length, &mut self.noise.enabled, val, 64, extra_clock);
+                if val & TRIGGER > 0 {
+                    self.noise.trigger();
+                }
+            },
+            _ => {},
+        }
+    }
+
+    fn trigger_sweep(&mut self) {
+        let sweep = &mut self.sweep;
+        sweep.shadow = self.pulse1.freq;
+        sweep.timer = if sweep.period == 0 { 8 } else { sweep.period };
+        sweep.enabled = sweep.period > 0 || sweep.shift > 0;
+        sweep.negate_used = false;
+        if sweep.shift > 0 && sweep.calculate() > 2047 {
+            self.pulse1.enabled = false;
+        }
+    }
+
+    fn clock_sweep(&mut self) {
+        let sweep = &mut self.sweep;
+        sweep.timer = sweep.timer.saturating_sub(1);
+        if sweep.timer > 0 {
+            return;
+        }
+        sweep.timer = if sweep.period == 0 { 8 } else { sweep.period };
+        if !sweep.enabled || sweep.period == 0 {
+            return;
+        }
+        let freq = sweep.calculate();
+        if freq > 2047 {
+            self.pulse1.enabled = false;
+        } else if sweep.shift > 0 {
+            sweep.shadow = freq;
+            self.pulse1.freq = freq;
+            //書き戻した後にもう一度オーバーフローを確認する
+            if sweep.calculate() > 2047 {
+                self.pulse1.enabled = false;
+            }
+        }
+    }
+
+    /*
+    Step | Length | Sweep | Envelope
+    0    | Clock  | -     | -
+    1    | -      | -     | -
+    2    | Clock  | Clock | -
+    3    | -      | -     | -
+    4    | Clock  | -     | -
+    5    | -      | -     | -
+    6    | Clock  | Clock | -
+    7    | -      | -     | Clock
+    */
+    fn clock_frame_sequencer(&mut self) {
+        match self.fs_step {
+            0 | 2 | 4 | 6 => {
+                self.pulse1.enabled &= self.pulse1.length.clock();
+                self.pulse2.enabled &= self.pulse2.length.clock();
+                self.wave.enabled &= self.wave.length.clock();
+                self.noise.enabled &= self.noise.length.clock();
+                if self.fs_step & 2 > 0 {
+                    self.clock_sweep();
+                }
+            },
+            7 => {
+                self.pulse1.envelope.clock();
+                self.pulse2.envelope.clock();
+                self.noise.envelope.clock();
+            },
+            _ => {},
+        }
+        self.fs_step = (self.fs_step + 1) & 7;
+    }
+
+    //DACの入力0~15を-1.0~1.0に変換する DACがオフなら無音
+    fn dac_output(dac: bool, val: u8) -> f32 {
+        if dac {
+            1.0 - val as f32 / 7.5
+        } else {
+            0.0
+        }
+    }
+
+    //チャンネル1~4のDACの出力
+    fn channel_outputs(&self) -> [f32; 4] {
+        [
+            Self::dac_output(self.pulse1.dac, if self.pulse1.enabled { self.pulse1.output() } else { 0 }),
+            Self::dac_output(self.pulse2.dac, if self.pulse2.enabled { self.pulse2.output() } else { 0 }),
+            Self::dac_output(self.wave.dac, if self.wave.enabled { self.wave.output() } else { 0 }),
+            Self::dac_output(self.noise.dac, if self.noise.enabled { self.noise.output() } else { 0 }),
+        ]
+    }
+
+    fn mix(&self, outputs: [f32; 4]) -> (f32, f32) {
+        let nr50 = self.regs[0x14];
+        let nr51 = self.regs[0x15];
+        let (mut left, mut right) = (0.0, 0.0);
+        for (i, out) in outputs.iter().enumerate() {
+            if !self.is_audible(Channel::ALL[i]) {
+                continue;
+            }
+            if nr51 & (0x10 << i) > 0 {
+                left += out;
+            }
+            if nr51 & (0x01 << i) > 0 {
+                right += out;
+            }
+        }
+        let left_volume = ((nr50 >> 4) & 0x07) as f32 + 1.0;
+        let right_volume = (nr50 & 0x07) as f32 + 1.0;
+        (left / 4.0 * left_volume / 8.0, right / 4.0 * right_volume / 8.0)
+    }
+
+    //1マシンサイクル分進める divはタイマーの内部カウンタ
+    pub fn emulate_cycle(&mut self, div: u16) {
+        self.clock += 1;
+        let div_bit = div & (1 << 12) > 0;
+        if self.power {
+            if self.div_bit && !div_bit {
+                self.clock_frame_sequencer();
+            }
+            self.pulse1.tick(4);
+            self.pulse2.tick(4);
+            self.wave.tick(4, &self.wave_ram);
+            self.noise.tick(4);
+        }
+        self.div_bit = div_bit;
+
+        let outputs = if self.power { self.channel_outputs() } else { [0.0; 4] };
+        self.scope_clock += 1;
+        if self.scope_clock >= SCOPE_INTERVAL {
+            self.scope_clock = 0;
+            self.scope.push(outputs);
+        }
+
+        //振幅が変わったときだけ帯域制限したステップとして合成する
+        let (left, right) = if self.power { self.mix(outputs) } else { (0.0, 0.0) };
+        for (i, out) in [left, right].into_iter().enumerate() {
+            if out != self.last_output[i] {
+                self.blips[i].add_delta(self.blip_clock, out - self.last_output[i]);
+                self.last_output[i] = out;
+            }
+        }
+        self.blip_clock += 1;
+        if self.blip_clock >= BLIP_FRAME {
+            self.end_blip_frame();
+        }
+    }
+
+    //合成し終わったサンプルにハイパスフィルタをかけてsamplesに移す
+    fn end_blip_frame(&mut self) {
+        let mut outputs = [vec![], vec![]];
+        for (blip, out) in self.blips.iter_mut().zip(outputs.iter_mut()) {
+            blip.end_frame(self.blip_clock);
+            blip.read_samples(out);
+        }
+        self.blip_clock = 0;
+        let mut samples = Vec::with_capacity(outputs[0].len() * 2);
+        for (left, right) in outputs[0].iter().zip(outputs[1].iter()) {
+            for (i, val) in [*left, *right].into_iter().enumerate() {
+                let out = val - self.hpf_capacitor[i];
+                self.hpf_capacitor[i] = val - out * self.hpf_charge;
+                samples.push(out);
+            }
+        }
+        //書き込みに失敗したら録音をやめる 誰も取り出さなくても録音には全部残る
+        if let Some(recorder) = &mut self.recorder
+            && let Err(e) = recorder.write_samples(&samples)
+        {
+            self.record_error = Some(e);
+            self.recorder = None;
+        }
+        let max_samples = self.max_samples();
+        if self.samples.len() + samples.len() > max_samples {
+            self.samples.drain(..max_samples / 2);
+        }
+        self.samples.extend(samples);
+    }
+
+    pub fn set_muted(&mut self, ch: Channel, muted: bool) {
+        self.muted[ch as usize] = muted;
+    }
+
+    pub fn is_muted(&self, ch: Channel) -> bool {
+        self.muted[ch as usize]
+    }
+
+    pub fn set_solo(&mut self, ch: Channel, solo: bool) {
+        self.solo[ch as usize] = solo;
+    }
+
+    pub fn is_solo(&self, ch: Channel) -> bool {
+        self.solo[ch as usize]
+    }
+
+    //ミュートされておらず、ソロ指定があるならソロに含まれているチャンネルが聞こえる
+    pub fn is_audible(&self, ch: Channel) -> bool {
+        let ch = ch as usize;
+        !self.muted[ch] && (self.solo[ch] || !self.solo.iter().any(|&s| s))
+    }
+
+    //各チャンネルの最近の出力をLCDと同じ大きさ・形式で描く
+    pub fn render_scope(&self) -> Vec<u8> {
+        self.scope.render(Channel::ALL.map(|ch| self.is_audible(ch)))
+    }
+
+    //これ以降のレジスタへの書き込みをpathにVGM形式で記録する
+    //最初に今のレジスタの値を書き込んでおく(鳴っている音は再生し直さない)
+    pub fn start_vgm(&mut self, path: &Path) -> io::Result<()> {
+        self.stop_vgm()?;
+        let mut vgm = VgmWriter::new(BufWriter::new(File::create(path)?), self.clock);
+        vgm.write_register(self.clock, 0xFF26, APU_ENABLE * self.power as u8);
+        for (i, &val) in self.wave_ram.iter().enumerate() {
+            vgm.write_register(self.clock, 0xFF30 + i as u16, val);
+        }
+        if self.power {
+            for (i, &val) in self.regs[..0x16].iter().enumerate() {
+                let addr = 0xFF10 + i as u16;
+                //NRx4はトリガーを除く
+                let val = match addr {
+                    0xFF14 | 0xFF19 | 0xFF1E | 0xFF23 => val & !TRIGGER,
+                    _ => val,
+                };
+                vgm.write_register(self.clock, addr, val);
+            }
+        }
+        self.vgm = Some(vgm);
+        Ok(())
+    }
+
+    pub fn is_logging_vgm(&self) -> bool {
+        self.vgm.is_some()
+    }
+
+    //VGMの記録を終えてファイルに書き出す
+    pub fn stop_vgm(&mut self) -> io::Result<()> {
+        match self.vgm.take() {
+            Some(vgm) => vgm.finish(self.clock).map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    //これから出力するサンプルをpathのWAVファイルに書き出す 録音中なら前の録音を終える
+    pub fn start_recording(&mut self, path: &Path) -> io::Result<()> {
+        self.stop_recording()?;
+        self.end_blip_frame();
+        self.recorder = Some(WavWriter::create(path, self.sample_rate)?);
+        Ok(())
+    }
+
+    pub fn is_recording(&self) -> bool {
+        self.recorder.is_some()
+    }
+
+    //録音を終えてファイルを閉じる 録音中に書き込みに失敗していればそのエラーを返す
+    pub fn stop_recording(&mut self) -> io::Result<()> {
+        if self.recorder.is_some() {
+            self.end_blip_frame();
+        }
+        if let Some(e) = self.record_error.take() {
+            return Err(e);
+        }
+        match self.recorder.take() {
+            Some(recorder) => recorder.finish().map(|_| ()),
+            None => Ok(()),
+        }
+    }
+
+    //溜まったサンプル(L, Rの順)を全部取り出す
+    pub fn drain_samples(&mut self) -> Vec<f32> {
+        self.end_blip_frame();
+        std::mem::take(&mut self.samples)
+    }
+}
+
+#[cfg(test)]
+mod unit_test {
+    use super::{Apu, Channel, SAMPLE_RATE};
+    use crate::gameboy::CPU_CLOCK_HZ;
+
+    //DIVのbit12が立ち下がるまで進める(フレームシーケンサ1ステップ分)
+    fn clock_frame_sequencer(apu: &mut Apu) {
+        apu.emulate_cycle(1 << 12);
+        apu.emulate_cycle(0);
+    }
+
+    fn apu() -> Apu {
+        let mut apu = Apu::new();
+        apu.write(0xFF26, 0x80);
+        apu
+    }
+
+    #[test]
+    fn test_read_masks() {
+        let mut apu = apu();
+        assert_eq!(0xF0, apu.read(0xFF26));
+        apu.write(0xFF11, 0x80 | 0x3F);
+        assert_eq!(0xBF, apu.read(0xFF11));
+        apu.write(0xFF13, 0x12);
+        assert_eq!(0xFF, apu.read(0xFF13));
+        assert_eq!(0xFF, apu.read(0xFF15));
+        apu.write(0xFF30, 0x5A);
+        assert_eq!(0x5A, apu.read(0xFF30));
+    }
+
+    #[test]
+    fn test_power_off() {
+        let mut apu = apu();
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF14, 0x80);
+        apu.write(0xFF24, 0x77);
+        apu.write(0xFF30, 0x5A);
+        assert_eq!(0xF1, apu.read(0xFF26));
+        apu.write(0xFF26, 0x00);
+        assert_eq!(0x70, apu.read(0xFF26));
+        assert_eq!(0x00, apu.read(0xFF24));
+        assert_eq!(0x00, apu.read(0xFF12));
+        //電源オフ中の書き込みは無視される
+        apu.write(0xFF24, 0x77);
+        assert_eq!(0x00, apu.read(0xFF24));
+        //波形メモリは残る
+        assert_eq!(0x5A, apu.read(0xFF30));
+    }
+
+    #[test]
+    fn test_length_counter() {
+        let mut apu = apu();
+        apu.write(0xFF17, 0xF0);
+        apu.write(0xFF16, 64 - 2);
+        apu.write(0xFF19, 0xC0);
+        assert_eq!(0xF2, apu.read(0xFF26));
+        clock_frame_sequencer(&mut apu);
+        assert_eq!(0xF2, apu.read(0xFF26));
+        clock_frame_sequencer(&mut apu);
+        clock_frame_sequencer(&mut apu);
+        assert_eq!(0xF0, apu.read(0xFF26));
+    }
+
+    #[test]
+    fn test_length_kept_on_power_off() {
+        let mut apu = apu();
+        apu.write(0xFF17, 0xF0);
+        apu.write(0xFF16, 64 - 1);
+        apu.write(0xFF26, 0x00);
+        apu.write(0xFF26, 0x80);
+        apu.write(0xFF17, 0xF0);
+        apu.write(0xFF19, 0xC0);
+        clock_frame_sequencer(&mut apu);
+        assert_eq!(0xF0, apu.read(0xFF26));
+    }
+
+    #[test]
+    fn test_dac_off_disables_channel() {
+        let mut apu = apu();
+        apu.write(0xFF1A, 0x80);
+        apu.write(0xFF1E, 0x80);
+        assert_eq!(0xF4, apu.read(0xFF26));
+        apu.write(0xFF1A, 0x00);
+        assert_eq!(0xF0, apu.read(0xFF26));
+    }
+
+    #[test]
+    fn test_sweep_overflow() {
+        let mut apu = apu();
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF10, 0x11);
+        apu.write(0xFF13, 0xFF);
+        apu.write(0xFF14, 0x87);
+        //トリガー時の計算で2047を超えるので止まる
+        assert_eq!(0xF0, apu.read(0xFF26));
+    }
+
+    #[test]
+    fn test_samples() {
+        let mut apu = apu();
+        for _ in 0..CPU_CLOCK_HZ / 4 {
+            apu.emulate_cycle(0);
+        }
+        assert_eq!(SAMPLE_RATE as usize * 2, apu.drain_samples().len());
+        assert!(apu.drain_samples().is_empty());
+        apu.set_sample_rate(32768);
+        for _ in 0..CPU_CLOCK_HZ / 4 {
+            apu.emulate_cycle(0);
+        }
+        assert_eq!(32768 * 2, apu.drain_samples().len());
+    }
+
+    #[test]
+    fn test_mute_solo() {
+        let mut apu = apu();
+        //チャンネル1と2のDACを有効にして両方左右に出す
+        apu.write(0xFF25, 0x33);
+        apu.write(0xFF24, 0x77);
+        apu.write(0xFF12, 0xF0);
+        apu.write(0xFF17, 0xF0);
+        let outputs = apu.channel_outputs();
+        let both = apu.mix(outputs);
+        apu.set_muted(Channel::Pulse1, true);
+        let pulse2 = apu.mix(outputs);
+        assert!(!apu.is_audible(Channel::Pulse1));
+        assert!((pulse2.0 - both.0 / 2.0).abs() < 1e-6);
+        //ソロはミュートより弱い
+        apu.set_solo(Channel::Pulse1, true);
+        assert!(!apu.is_audible(Channel::Pulse2));
+        assert_eq!((0.0, 0.0), apu.mix(outputs));
+        apu.set_muted(Channel::Pulse1, false);
+        assert!(apu.is_audible(Channel::Pulse1));
+        assert!(!apu.is_audible(Channel::Noise));
+        assert!((apu.mix(outputs).0 - both.0 / 2.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_high_pass_filter() {
+        let mut apu = apu();
+        //DACだけ有効にして一定の出力を続ける
+        apu.write(0xFF25, 0x11);
+        apu.write(0xFF24, 0x77);
+        apu.write(0xFF12, 0xF0);
+        for _ in 0..CPU_CLOCK_HZ / 4 {
+            apu.emulate_cycle(0);
+        }
+        let samples = apu.drain_samples();
+        let peak = samples.iter().fold(0.0f32, |a, s| a.max(s.abs()));
+        assert!(peak > 0.1);
+        //直流成分はコンデンサで抜ける
+        assert!(samples[samples.len() - 1].abs() < peak * 0.01);
+    }
+}
@@ -0,0 +1,92 @@
+use std::thread;
+use std::time::Duration;
+
+use sdl2::audio::{AudioQueue, AudioSpecDesired};
+use sdl2::Sdl;
+
+use crate::apu::SAMPLE_RATE;
+
+//キューに溜めておく目標の長さ
+const TARGET_LATENCY_MS: u32 = 50;
+//再生速度の調整幅 ±0.5%までなら音程のずれは聞き取れない
+const MAX_RATE_DELTA: f64 = 0.005;
+
+//APUのサンプルをSDLのAudioQueueで再生する
+//キューの残量が目標に近づくように再サンプリングの比率を少しずつ変えて、音切れと映像とのずれを防ぐ
+pub struct Audio {
+    queue: AudioQueue<f32>,
+    target: u32, //目標のキューの長さ(フレーム数)
+    pos: f64, //入力サンプル上の次に出力する位置
+    last: [f32; 2], //前回の入力の最後のフレーム 補間に使う
+}
+impl Audio {
+    pub fn new(sdl: &Sdl) -> Result<Self, String> {
+        let spec = AudioSpecDesired {
+            freq: Some(SAMPLE_RATE as i32),
+            channels: Some(2),
+            samples: Some(1024),
+        };
+        let queue = sdl.audio()?.open_queue::<f32, _>(None, &spec)?;
+        queue.resume();
+        let target = queue.spec().freq as u32 * TARGET_LATENCY_MS / 1000;
+        Ok(Self {
+            queue,
+            target,
+            pos: 0.0,
+            last: [0.0; 2],
+        })
+    }
+
+    fn queued_frames(&self) -> u32 {
+        self.queue.size() / (2 * std::mem::size_of::<f32>() as u32)
+    }
+
+    //デバイスの実際のサンプルレート GameBoyの出力をこれに合わせると再サンプリングの比率がほぼ1になる
+    pub fn freq(&self) -> u32 {
+        self.queue.spec().freq as u32
+    }
+
+    //samplesはL, Rの順でsample_rate Hz
+    pub fn push(&mut self, samples: &[f32], sample_rate: u32) {
+        let queued = self.queued_frames();
+        //早送り中などで溜まりすぎたら捨てる
+        if queued > self.target * 4 {
+            return;
+        }
+        //キューが少なければ多めに、多ければ少なめに出力する
+        let fill = queued as f64 / self.target as f64;
+        let ratio = 1.0 + MAX_RATE_DELTA * (1.0 - fill).clamp(-1.0, 1.0);
+        let step = sample_rate as f64 / (self.freq() as f64 * ratio);
+
+        let frames = samples.len() / 2;
+        let frame = |i: usize| if i == 0 {
+            self.last
+        } else {
+            [samples[(i - 1) * 2], samples[(i - 1) * 2 + 1]]
+        };
+        let mut out = Vec::with_capacity((frames as f64 / step) as usize * 2 + 2);
+        //直線補間 0番目は前回の最後のフレーム
+        while self.pos + 1.0 <= frames as f64 {
+            let i = self.pos as usize;
+            let frac = (self.pos - i as f64) as f32;
+            let (a, b) = (frame(i), frame(i + 1));
+            out.push(a[0] + (b[0] - a[0]) * frac);
+            out.push(a[1] + (b[1] - a[1]) * frac);
+            self.pos += step;
+        }
+        self.pos -= frames as f64;
+        if frames > 0 {
+            self.last = frame(frames);
+        }
+        if let Err(e) = self.queue.queue_audio(&out) {
+            eprintln!("failed to queue audio: {}", e);
+        }
+    }
+
+    //キューが目標の長さまで減るのを待つ 実機の速さに合わせるのに使う
+    pub fn wait(&self) {
+        while self.queued_frames() > self.target {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
@@ -0,0 +1,100 @@
+use std::env;
+use std::fs;
+use std::process::ExitCode;
+
+use gbemu_rust::bootrom::Bootrom;
+use gbemu_rust::cartridge::Cartridge;
+use gbemu_rust::testrom::{TestResult, TestRunner};
+
+//おおよそ2分弱(エミュレータ内の時間)
+const DEFAULT_MAX_CYCLES: u64 = 120_000_000;
+
+const EXIT_PASSED: u8 = 0;
+const EXIT_FAILED: u8 = 1;
+const EXIT_TIMEOUT: u8 = 2;
+const EXIT_USAGE: u8 = 3;
+
+const USAGE: &str = "\
+usage: gbemu-test [--cycles N] [--bootrom PATH] ROM...
+
+  --cycles N      run each ROM for at most N machine cycles (default 120000000)
+  --bootrom PATH  run the boot ROM before the test ROM (default: skip boot)";
+
+fn main() -> ExitCode {
+    let mut max_cycles = DEFAULT_MAX_CYCLES;
+    let mut bootrom_path = None;
+    let mut roms = vec![];
+
+    let mut args = env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--cycles" => match args.next().and_then(|v| v.parse().ok()) {
+                Some(n) => max_cycles = n,
+                None => return usage("--cycles needs a number"),
+            },
+            "--bootrom" => match args.next() {
+                Some(path) => bootrom_path = Some(path),
+                None => return usage("--bootrom needs a path"),
+            },
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                return ExitCode::from(EXIT_PASSED);
+            },
+            _ => roms.push(arg),
+        }
+    }
+    if roms.is_empty() {
+        return usage("no ROM given");
+    }
+
+    let mut exit = EXIT_PASSED;
+    let (mut passed, mut failed, mut timeout) = (0, 0, 0);
+    for rom in &roms {
+        let bootrom = match &bootrom_path {
+            Some(path) => match fs::read(path) {
+                Ok(data) => Bootrom::new(data),
+                Err(e) => return usage(&format!("cannot read {}: {}", path, e)),
+            },
+            None => Bootrom::disabled(),
+        };
+        let cartridge = match fs::read(rom) {
+            Ok(data) => match Cartridge::new(data.into_boxed_slice()) {
+                Ok(cartridge) => cartridge,
+                Err(e) => return usage(&format!("cannot load {}: {}", rom, e)),
+            },
+            Err(e) => return usage(&format!("cannot read {}: {}", rom, e)),
+        };
+
+        let mut runner = TestRunner::new(bootrom, cartridge);
+        let result = runner.run(max_cycles);
+        match result {
+            TestResult::Passed => {
+                passed += 1;
+                println!("PASS    {} ({} cycles)", rom, runner.cycles());
+            },
+            TestResult::Failed(ref reason) => {
+                failed += 1;
+                exit = EXIT_FAILED;
+                println!("FAIL    {} ({} cycles): {}", rom, runner.cycles(), reason);
+            },
+            TestResult::Timeout => {
+                timeout += 1;
+                if exit == EXIT_PASSED {
+                    exit = EXIT_TIMEOUT;
+                }
+                println!("TIMEOUT {} ({} cycles)", rom, runner.cycles());
+            },
+        }
+        let output = runner.serial_output();
+        if result != TestResult::Passed && !output.is_empty() {
+            println!("--- serial output ---\n{}\n---------------------", output.trim_end());
+        }
+    }
+    println!("\n{} passed, {} failed, {} timed out", passed, failed, timeout);
+    ExitCode::from(exit)
+}
+
+fn usage(msg: &str) -> ExitCode {
+    eprintln!("error: {}\n\n{}", msg, USAGE);
+    ExitCode::from(EXIT_USAGE)
+}
@@ -0,0 +1,117 @@
+use std::f64::consts::PI;
+
+//帯域制限したステップの幅(出力サンプル数)と、1サンプルを何分割した位置で近似するか
+const WIDTH: usize = 16;
+const PHASES: usize = 32;
+//出力のナイキスト周波数に対するカットオフ
+const CUTOFF: f64 = 0.45;
+
+//振幅の変化(delta)を入力クロックの時刻で受け取り、帯域制限したステップとして出力サンプルに合成する
+//矩形波を単純に点サンプリングしたときに出る折り返しノイズを防ぐ
+pub struct BlipBuffer {
+    factor: f64, //入力1クロックあたりの出力サンプル数
+    offset: f64, //フレームの先頭の出力サンプル上の位置
+    buf: Vec<f32>, //出力サンプルごとの変化量 積分すると波形になる
+    integrator: f32,
+    kernel: Vec<[f32; WIDTH]>, //位相ごとのインパルス応答(合計1)
+}
+impl BlipBuffer {
+    //max_frameは1フレーム(end_frameの間隔)の最大入力クロック数
+    pub fn new(clock_rate: f64, sample_rate: f64, max_frame: u32) -> Self {
+        let factor = sample_rate / clock_rate;
+        let size = (max_frame as f64 * factor).ceil() as usize + WIDTH + 2;
+        Self {
+            factor,
+            offset: 0.0,
+            buf: vec![0.0; size],
+            integrator: 0.0,
+            kernel: (0..=PHASES).map(|phase| Self::make_kernel(phase as f64 / PHASES as f64)).collect(),
+        }
+    }
+
+    //窓関数(Blackman)を掛けたsinc関数 fracだけ遅れた位置のインパルスを表す
+    fn make_kernel(frac: f64) -> [f32; WIDTH] {
+        let mut kernel = [0.0; WIDTH];
+        let half = (WIDTH / 2) as f64;
+        for (j, k) in kernel.iter_mut().enumerate() {
+            let x = j as f64 - frac - (half - 1.0);
+            let sinc = if x == 0.0 {
+                1.0
+            } else {
+                (PI * 2.0 * CUTOFF * x).sin() / (PI * 2.0 * CUTOFF * x)
+            };
+            let t = x / half;
+            let window = if t.abs() >= 1.0 {
+                0.0
+            } else {
+                0.42 + 0.5 * (PI * t).cos() + 0.08 * (2.0 * PI * t).cos()
+            };
+            *k = sinc * window;
+        }
+        let sum = kernel.iter().sum::<f64>();
+        kernel.map(|k| (k / sum) as f32)
+    }
+
+    //フレームの先頭からtimeクロック目で振幅がdeltaだけ変わった
+    pub fn add_delta(&mut self, time: u32, delta: f32) {
+        let pos = self.offset + time as f64 * self.factor;
+        let i = pos as usize;
+        let phase = ((pos - i as f64) * PHASES as f64).round() as usize;
+        for (b, k) in self.buf[i..i + WIDTH].iter_mut().zip(self.kernel[phase].iter()) {
+            *b += delta * k;
+        }
+    }
+
+    //timeクロックでフレームを終える 出力できるサンプルが増える
+    pub fn end_frame(&mut self, time: u32) {
+        self.offset += time as f64 * self.factor;
+    }
+
+    //出力できるサンプルを全部outに追加する
+    pub fn read_samples(&mut self, out: &mut Vec<f32>) {
+        let n = self.offset as usize;
+        for &delta in self.buf[..n].iter() {
+            self.integrator += delta;
+            out.push(self.integrator);
+        }
+        self.buf.copy_within(n.., 0);
+        let len = self.buf.len();
+        self.buf[len - n..].fill(0.0);
+        self.offset -= n as f64;
+    }
+}
+
+#[cfg(test)]
+mod unit_test {
+    use super::{BlipBuffer, WIDTH};
+
+    #[test]
+    fn test_step() {
+        let mut blip = BlipBuffer::new(1000.0, 100.0, 1000);
+        blip.add_delta(55, 1.0);
+        blip.end_frame(1000);
+        let mut out = vec![];
+        blip.read_samples(&mut out);
+        assert_eq!(100, out.len());
+        //ステップより十分前は0、十分後は1に落ち着く
+        assert!(out[0].abs() < 1e-6);
+        assert!((out[5 + WIDTH] - 1.0).abs() < 1e-6);
+        assert!((out[99] - 1.0).abs() < 1e-6);
+        //途中はなめらかに変化する
+        assert!(out[5 + WIDTH / 2 - 1] > 0.2 && out[5 + WIDTH / 2 - 1] < 0.8);
+    }
+
+    #[test]
+    fn test_frames() {
+        let mut blip = BlipBuffer::new(1000.0, 300.0, 100);
+        let mut out = vec![];
+        for _ in 0..10 {
+            blip.add_delta(0, 0.5);
+            blip.add_delta(50, -0.5);
+            blip.end_frame(100);
+            blip.read_samples(&mut out);
+        }
+        //端数は次のフレームに持ち越される
+        assert_eq!(300, out.len());
+    }
+}
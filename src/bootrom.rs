@@ -11,6 +11,11 @@ impl Bootrom {
         Self {rom, 
             active: true}
     }
+    //ブートROM無しで起動するときは最初から無効にしておく
+    pub fn disabled() -> Self {
+        Self {rom: vec![],
+            active: false}
+    }
     pub fn read(&self, addr: u16) -> u8 {
         self.rom[addr as usize]
     }
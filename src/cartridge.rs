@@ -1,125 +1,413 @@
-use core::panic;
+use crate::gbs::Gbs;
+use crate::mbc::{self, GbsMapper, Mapper};
+use crate::rtc::ClockSource;
 
-use crate::mbc::Mbc;
+pub use crate::header::{CartridgeHeader, HeaderError};
 
 pub struct Cartridge {
-    rom: Box<[u8]>,
-    sram: Box<[u8]>,
-    mbc: Mbc,
+    mapper: Box<dyn Mapper>,
+    battery: bool, //電源を切ってもSRAM(とRTC)が消えない
+    sram_dirty: bool, //最後にsave_dataで書き出してからSRAMに書き込まれた
 }
 impl Cartridge {
-    pub fn new(rom: Box<[u8]>) -> Self {
-        let header = CartridgeHeader::new(rom[0x100..0x150].try_into().unwrap());
-        let title = str::from_utf8(&header.title).unwrap().trim_end_matches('\0').to_string();
-        let rom_size = header.rom_size();
-        let sram_size = header.sram_size();
-        let rom_banks = rom_size >> 14;
-        let mbc = Mbc::new(header.cartridge_type[0], rom_banks);
-        println!("cartridge info {{ title: {}, type: {}, rom_size: {}B, sram_size: {}B }}",
-        title,
-        match mbc {
-            Mbc::NoMbc { .. } => "NO MBC",
-            Mbc::Mbc1 { .. } => "MBC1",
-        },
+    pub fn new(rom: Box<[u8]>) -> Result<Self, HeaderError> {
+        let header = CartridgeHeader::parse(&rom)?;
+        //実機のブートROMはロゴとヘッダのチェックサムが合わないと止まるが、ここでは警告だけ出す
+        if !header.logo_valid {
+            eprintln!("warning: Nintendo logo in the header does not match");
+        }
+        if !header.header_checksum_valid {
+            eprintln!("warning: header checksum validation failed");
+        }
+        if rom.len() != header.rom_size {
+            eprintln!("warning: header says {} bytes of ROM, got {}", header.rom_size, rom.len());
+        }
+        let info = mbc::lookup(header.cartridge_type)
+            .ok_or(HeaderError::UnsupportedType(header.cartridge_type))?;
+        let rom = pad_rom(rom);
+        let rom_size = rom.len();
+        let mapper = (info.new)(rom, header.sram_size);
+        eprintln!("cartridge info {{ title: {}, type: {}, rom_size: {}B, sram_size: {}B, licensee: {} }}",
+        header.title,
+        info.name,
         rom_size,
-        sram_size,
+        mapper.ram().len(),
+        header.licensee().unwrap_or("Unknown"),
         );
-        assert!(rom.len() == rom_size, "Expected {} bytes of cartridge ROM, got {}", rom_size, rom.len());
+        Ok(Self {
+            mapper,
+            battery: info.battery,
+            sram_dirty: false,
+        })
+    }
+
+    //GBSのデータをヘッダ無しのROMとして読み込む
+    pub fn from_gbs(gbs: &Gbs) -> Self {
         Self {
-            rom,
-            sram: vec![0; sram_size].into(),
-            mbc,
+            mapper: Box::new(GbsMapper::new(gbs.rom_image())),
+            battery: false,
+            sram_dirty: false,
+        }
+    }
+
+    pub fn has_battery(&self) -> bool {
+        self.battery
+    }
+
+    pub fn is_sram_dirty(&self) -> bool {
+        self.sram_dirty
+    }
+
+    //.savファイルに書く内容
+    pub fn save_data(&mut self) -> Vec<u8> {
+        self.sram_dirty = false;
+        self.mapper.save_data()
+    }
+
+    pub fn load_save_data(&mut self, data: &[u8]) {
+        self.mapper.load_save_data(data);
+        self.sram_dirty = false;
+    }
+
+    //RTCを進める時計を差し替える RTCが無ければ何もしない
+    pub fn set_clock_source(&mut self, clock: Box<dyn ClockSource>) {
+        if let Some(rtc) = self.mapper.rtc_mut() {
+            rtc.set_clock_source(clock);
         }
     }
 
+    pub fn rumble(&self) -> bool {
+        self.mapper.rumble()
+    }
+
+    //加速度センサーが無ければ何もしない
+    pub fn set_tilt(&mut self, x: f32, y: f32) {
+        self.mapper.set_tilt(x, y);
+    }
+
     pub fn read(&self, addr: u16) -> u8 {
         match addr {
-            0x0000..=0x7FFF => self.rom[self.mbc.get_addr(addr) & (self.rom.len() - 1)],
-            0xA000..=0xBFFF => match self.mbc {
-                Mbc::NoMbc => self.sram[addr as usize & (self.sram.len() - 1)],
-                Mbc::Mbc1 { ref sram_enable, .. } => if *sram_enable {
-                    self.sram[self.mbc.get_addr(addr) & (self.sram.len() - 1)]
-                } else {
-                    0xFF
-                },
-            },
-        _ => unreachable!(),
+            0x0000..=0x7FFF => self.mapper.read_rom(addr),
+            0xA000..=0xBFFF => self.mapper.read_ram(addr),
+            _ => unreachable!(),
         }
     }
 
     pub fn write(&mut self, addr: u16, val: u8) {
         match addr {
-            0x0000..=0x7FFF => self.mbc.write(addr, val),
-            0xA000..=0xBFFF => match self.mbc {
-                Mbc::NoMbc => self.sram[addr as usize & (self.sram.len() - 1)] = val,
-                Mbc::Mbc1 { ref sram_enable, .. } => if *sram_enable {
-                    self.sram[self.mbc.get_addr(addr) & (self.sram.len() - 1)] = val;
-                },
+            0x0000..=0x7FFF => self.mapper.write_register(addr, val),
+            0xA000..=0xBFFF => if self.mapper.write_ram(addr, val) {
+                self.sram_dirty = true;
             },
             _ => unreachable!(),
         }
     }
 }
 
-#[repr(C)]
-pub struct CartridgeHeader {
-    entry_point: [u8; 4],
-    logo: [u8; 48],
-    title: [u8; 11], //ascii game title
-    maker: [u8; 4],
-    cdb_flag: [u8; 1],
-    new_licensee: [u8; 2],
-    sgb_flag: [u8; 1],
-
-    /*
-    cartridge_type
-    0x00 ROM Only
-    0x01 MBC1
-    0x02 MBC1 + SRAM
-    0x03 MBC1 + SRAM + Battery
-    0x08 ROM  + SRAM
-    0x09 ROM  + SRAM + Battery
-     */
-    cartridge_type: [u8; 1],
-    rom_size: [u8; 1],
-    sram_size: [u8; 1],
-    destination: [u8; 1],
-    old_licensee: [u8; 1],
-    game_version: [u8; 1],
-    header_chechsum: [u8; 1],
-    global_checksum: [u8; 2],
+//バンクのマスクが効くように2の累乗(最低32KiB)まで0xFFで埋める
+//埋めた先より上のバンクは下のバンクの繰り返しになる
+fn pad_rom(rom: Box<[u8]>) -> Box<[u8]> {
+    let size = rom.len().next_power_of_two().max(0x8000);
+    if rom.len() == size {
+        return rom;
+    }
+    let mut rom = rom.into_vec();
+    rom.resize(size, 0xFF);
+    rom.into_boxed_slice()
 }
 
-impl CartridgeHeader {
-    fn new(data: [u8; 0x50]) -> Self {
-        let ret = unsafe {
-            std::mem::transmute::<[u8; 0x50], Self>(data)
-        };
-        let mut chksum: u8 = 0;
-        for i in 0x34..=0x4c {
-            chksum = chksum.wrapping_sub(data[i]).wrapping_sub(1)
+#[cfg(test)]
+pub(crate) mod unit_test {
+    use super::{Cartridge, HeaderError};
+    use crate::header::NINTENDO_LOGO;
+    use crate::mbc::{self, Mapper, MapperInfo, Ram};
+    use crate::header::unit_test::fix_header;
+    use crate::rtc;
+    use crate::rtc::unit_test::MockClock;
+
+    //バンクごとに先頭へバンク番号を書いたROM
+    pub(crate) fn rom(cartridge_type: u8, rom_size: u8, sram_size: u8) -> Box<[u8]> {
+        let mut rom = vec![0; 0x8000 << rom_size];
+        for (bank, chunk) in rom.chunks_mut(0x4000).enumerate() {
+            chunk[0] = bank as u8;
+            chunk[1] = (bank >> 8) as u8;
         }
-        assert!(chksum == ret.header_chechsum[0], "Checksum validation failed.");
-        ret
-    }
-    
-    fn rom_size(&self) -> usize {
-        assert!(self.rom_size[0] <= 0x08, "Invalid rom size {}.", self.rom_size[0]);
-        return 1 << (15 + self.rom_size[0]);
-    }
-
-    fn sram_size(&self) -> usize {
-        match self.sram_size[0] {
-            0x00 => 0,
-            0x01 => 0x800,
-            0x02 => 0x2000,
-            0x03 => 0x8000,
-            0x04 => 0x20000,
-            0x05 => 0x10000,
-            _ => panic!("Invalid sram size {}.", self.sram_size[0]),
+        rom[0x147] = cartridge_type;
+        rom[0x148] = rom_size;
+        rom[0x149] = sram_size;
+        fix_header(&mut rom);
+        rom.into_boxed_slice()
+    }
+
+    #[test]
+    fn test_mbc1() {
+        //2MiB, 32KiB SRAM
+        let mut cart = Cartridge::new(rom(0x03, 0x06, 0x03)).unwrap();
+        assert_eq!(1, cart.read(0x4000));
+        cart.write(0x2000, 0x00);
+        assert_eq!(1, cart.read(0x4000));
+        //下位4bitが0でも0x10は選べる
+        cart.write(0x2000, 0x10);
+        assert_eq!(0x10, cart.read(0x4000));
+        //0x20, 0x40, 0x60は0x21, 0x41, 0x61になる
+        cart.write(0x4000, 0x01);
+        cart.write(0x2000, 0x00);
+        assert_eq!(0x21, cart.read(0x4000));
+        assert_eq!(0x00, cart.read(0x0000));
+        cart.write(0x2000, 0xE3);
+        assert_eq!(0x23, cart.read(0x4000));
+        //モード1では0x0000~0x3FFFも上位bitで切り替わる
+        cart.write(0x6000, 0x01);
+        assert_eq!(0x20, cart.read(0x0000));
+        cart.write(0x0000, 0x0A);
+        cart.write(0xA000, 0x11);
+        cart.write(0x4000, 0x02);
+        cart.write(0xA000, 0x22);
+        assert_eq!(0x43, cart.read(0x4000));
+        cart.write(0x4000, 0x01);
+        assert_eq!(0x11, cart.read(0xA000));
+        //モード0ではSRAMはバンク0
+        cart.write(0x6000, 0x00);
+        assert_eq!(0x00, cart.read(0xA000));
+        assert_eq!(0x00, cart.read(0x0000));
+
+        //512KiBでは上位bitが繋がっていない
+        let mut cart = Cartridge::new(rom(0x01, 0x04, 0x00)).unwrap();
+        cart.write(0x4000, 0x01);
+        cart.write(0x2000, 0x05);
+        assert_eq!(0x05, cart.read(0x4000));
+        cart.write(0x6000, 0x01);
+        assert_eq!(0x00, cart.read(0x0000));
+    }
+
+    #[test]
+    fn test_mbc1m() {
+        let mut multicart = rom(0x01, 0x05, 0x00).into_vec();
+        multicart[0x40104..0x40134].copy_from_slice(&NINTENDO_LOGO);
+        let mut cart = Cartridge::new(multicart.into_boxed_slice()).unwrap();
+        cart.write(0x4000, 0x01);
+        cart.write(0x2000, 0x02);
+        assert_eq!(0x12, cart.read(0x4000));
+        //low_bankの4bit目は繋がっていない
+        cart.write(0x2000, 0x13);
+        assert_eq!(0x13, cart.read(0x4000));
+        //0かどうかは5bitで判定する
+        cart.write(0x2000, 0x10);
+        assert_eq!(0x10, cart.read(0x4000));
+        cart.write(0x2000, 0x00);
+        assert_eq!(0x11, cart.read(0x4000));
+        cart.write(0x4000, 0x03);
+        cart.write(0x6000, 0x01);
+        assert_eq!(0x30, cart.read(0x0000));
+        //ロゴが無ければ普通のMBC1
+        let mut cart = Cartridge::new(rom(0x01, 0x05, 0x00)).unwrap();
+        cart.write(0x4000, 0x01);
+        cart.write(0x2000, 0x02);
+        assert_eq!(0x22, cart.read(0x4000));
+    }
+
+    #[test]
+    fn test_mbc3_banks() {
+        //1MiB, 32KiB SRAM
+        let mut cart = Cartridge::new(rom(0x13, 0x05, 0x03)).unwrap();
+        assert_eq!(1, cart.read(0x4000));
+        cart.write(0x2000, 0x00);
+        assert_eq!(1, cart.read(0x4000));
+        cart.write(0x2000, 0x3F);
+        assert_eq!(0x3F, cart.read(0x4000));
+        //有効にするまでSRAMは読み書きできない
+        cart.write(0xA000, 0x12);
+        assert_eq!(0xFF, cart.read(0xA000));
+        cart.write(0x0000, 0x0A);
+        cart.write(0x4000, 0x02);
+        cart.write(0xA000, 0x12);
+        cart.write(0x4000, 0x01);
+        assert_eq!(0x00, cart.read(0xA000));
+        cart.write(0x4000, 0x02);
+        assert_eq!(0x12, cart.read(0xA000));
+    }
+
+    #[test]
+    fn test_mbc5() {
+        //8MiB, 128KiB SRAM
+        let mut cart = Cartridge::new(rom(0x1B, 0x08, 0x04)).unwrap();
+        assert_eq!(1, cart.read(0x4000));
+        //バンク0も選べる
+        cart.write(0x2000, 0x00);
+        assert_eq!([0x00, 0x00], [cart.read(0x4000), cart.read(0x4001)]);
+        cart.write(0x2000, 0xFF);
+        cart.write(0x3000, 0x01);
+        assert_eq!([0xFF, 0x01], [cart.read(0x4000), cart.read(0x4001)]);
+        cart.write(0x0000, 0x0A);
+        cart.write(0x4000, 0x0F);
+        cart.write(0xA000, 0x34);
+        cart.write(0x4000, 0x07);
+        assert_eq!(0x00, cart.read(0xA000));
+        cart.write(0x4000, 0x0F);
+        assert_eq!(0x34, cart.read(0xA000));
+        assert!(!cart.rumble());
+    }
+
+    #[test]
+    fn test_mbc5_rumble() {
+        let mut cart = Cartridge::new(rom(0x1E, 0x01, 0x03)).unwrap();
+        cart.write(0x0000, 0x0A);
+        cart.write(0x4000, 0x08 | 0x01);
+        assert!(cart.rumble());
+        cart.write(0xA000, 0x56);
+        //bit3はRAMのバンクに含まれない
+        cart.write(0x4000, 0x01);
+        assert!(!cart.rumble());
+        assert_eq!(0x56, cart.read(0xA000));
+    }
+
+    #[test]
+    fn test_mbc2() {
+        //256KiB ヘッダのSRAMの大きさは0
+        let mut cart = Cartridge::new(rom(0x06, 0x03, 0x00)).unwrap();
+        //bit8が立っていればROMバンク
+        cart.write(0x2100, 0x0F);
+        assert_eq!(0x0F, cart.read(0x4000));
+        cart.write(0x0100, 0x00);
+        assert_eq!(1, cart.read(0x4000));
+        //bit8が0ならRAMの有効化
+        cart.write(0xA000, 0x0A);
+        assert_eq!(0xFF, cart.read(0xA000));
+        cart.write(0x3E00, 0x0A);
+        cart.write(0xA000, 0x5A);
+        assert_eq!(0xFA, cart.read(0xA000));
+        assert_eq!(0xFA, cart.read(0xA200));
+        assert_eq!(0xFA, cart.read(0xBE00));
+        cart.write(0xA1FF, 0x03);
+        assert_eq!(0xF3, cart.read(0xBFFF));
+    }
+
+    #[test]
+    fn test_mbc3_rtc() {
+        let clock = MockClock::default();
+        let mut cart = Cartridge::new(rom(0x10, 0x00, 0x02)).unwrap();
+        cart.set_clock_source(Box::new(clock.clone()));
+        cart.write(0x0000, 0x0A);
+        clock.advance(61);
+        cart.write(0x6000, 0x00);
+        cart.write(0x6000, 0x01);
+        cart.write(0x4000, 0x08);
+        assert_eq!(1, cart.read(0xA000));
+        cart.write(0x4000, 0x09);
+        assert_eq!(1, cart.read(0xA123));
+        //RTCのレジスタへの書き込みはSRAMに届かない
+        cart.write(0xA000, 0x05);
+        cart.write(0x4000, 0x00);
+        assert_eq!(0x00, cart.read(0xA000));
+    }
+
+    #[test]
+    fn test_rom_size_mismatch() {
+        //ヘッダは128KiBだが3バンクしか無い
+        let mut trimmed = rom(0x19, 0x02, 0x00).into_vec();
+        trimmed.truncate(0xC000);
+        let mut cart = Cartridge::new(trimmed.into_boxed_slice()).unwrap();
+        cart.write(0x2000, 0x02);
+        assert_eq!(2, cart.read(0x4000));
+        cart.write(0x2000, 0x03);
+        assert_eq!(0xFF, cart.read(0x4000));
+        cart.write(0x2000, 0x05);
+        assert_eq!(1, cart.read(0x4000));
+
+        //ヘッダは32KiBだが128KiBある
+        let mut overdump = rom(0x19, 0x02, 0x00).into_vec();
+        overdump[0x148] = 0x00;
+        let mut cart = Cartridge::new(fix(overdump)).unwrap();
+        cart.write(0x2000, 0x07);
+        assert_eq!(7, cart.read(0x4000));
+
+        //32KiBより小さい自作ROM
+        let mut cart = Cartridge::new(fix(rom(0x00, 0x00, 0x00)[..0x4100].to_vec())).unwrap();
+        assert_eq!(0x00, cart.read(0x0000));
+        assert_eq!(0xFF, cart.read(0x7FFF));
+        cart.write(0xA000, 0x12);
+        assert_eq!(0xFF, cart.read(0xA000));
+    }
+
+    #[test]
+    fn test_unsupported_type() {
+        assert!(matches!(Cartridge::new(rom(0xFC, 0x00, 0x00)), Err(HeaderError::UnsupportedType(0xFC))));
+    }
+
+    //mbc.rsの外で書いたマッパー ROMの代わりに0x42が読める
+    struct TestMapper {
+        ram: Ram,
+    }
+    impl Mapper for TestMapper {
+        fn read_rom(&self, _addr: u16) -> u8 {
+            0x42
+        }
+        fn write_register(&mut self, addr: u16, val: u8) {
+            if addr < 0x2000 {
+                self.ram.write_enable(val);
+            }
+        }
+        fn read_ram(&self, addr: u16) -> u8 {
+            self.ram.read(0, addr)
+        }
+        fn write_ram(&mut self, addr: u16, val: u8) -> bool {
+            self.ram.write(0, addr, val)
+        }
+        fn ram(&self) -> &Ram {
+            &self.ram
+        }
+        fn ram_mut(&mut self) -> &mut Ram {
+            &mut self.ram
         }
     }
-}
 
+    #[test]
+    fn test_register() {
+        mbc::register(0xFD, MapperInfo {
+            name: "TEST",
+            battery: true,
+            new: |_, ram| Box::new(TestMapper { ram: Ram::new(ram) }),
+        });
+        let mut cart = Cartridge::new(rom(0xFD, 0x00, 0x02)).unwrap();
+        assert!(cart.has_battery());
+        assert_eq!(0x42, cart.read(0x4000));
+        cart.write(0x0000, 0x0A);
+        cart.write(0xA000, 0x12);
+        assert_eq!(0x12, cart.read(0xA000));
+    }
 
+    fn fix(mut rom: Vec<u8>) -> Box<[u8]> {
+        fix_header(&mut rom);
+        rom.into_boxed_slice()
+    }
 
+    #[test]
+    fn test_save_data() {
+        let clock = MockClock::default();
+        let mut cart = Cartridge::new(rom(0x10, 0x00, 0x02)).unwrap();
+        cart.set_clock_source(Box::new(clock.clone()));
+        assert!(cart.has_battery());
+        assert!(!cart.is_sram_dirty());
+        //RAMが無効なときの書き込みは捨てられるので書き出さなくてよい
+        cart.write(0xA010, 0x42);
+        assert!(!cart.is_sram_dirty());
+        cart.write(0x0000, 0x0A);
+        cart.write(0xA010, 0x42);
+        assert!(cart.is_sram_dirty());
+        let data = cart.save_data();
+        assert!(!cart.is_sram_dirty());
+        assert_eq!(0x2000 + rtc::SAVE_SIZE, data.len());
+
+        let mut loaded = Cartridge::new(rom(0x10, 0x00, 0x02)).unwrap();
+        loaded.set_clock_source(Box::new(clock.clone()));
+        clock.advance(2);
+        loaded.load_save_data(&data);
+        loaded.write(0x0000, 0x0A);
+        assert_eq!(0x42, loaded.read(0xA010));
+        loaded.write(0x6000, 0x00);
+        loaded.write(0x6000, 0x01);
+        loaded.write(0x4000, 0x08);
+        assert_eq!(2, loaded.read(0xA000));
+        assert!(!Cartridge::new(rom(0x02, 0x00, 0x02)).unwrap().has_battery());
+    }
+}
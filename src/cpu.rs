@@ -1,6 +1,6 @@
 use core::panic;
 
-use crate::interruputs::Interrupts;
+use crate::interruputs::{Interrupts, VBLANK, STAT, TIMER, SERIAL, JOYPAD};
 use crate::operand::Direct8;
 use crate::operand::Indirect;
 use crate::operand::*;
@@ -13,8 +13,10 @@ pub struct Ctx {
     opcode: u8,
     cb: bool,
     int: bool,
+    fetched: bool, //このサイクルで次の命令をフェッチした
 }
 
+//命令の実行が何サイクル目か 入れ子になった処理はそれぞれ別の状態を使う
 #[derive(Default)]
 pub struct ExecutionState {
     pub step: u8,
@@ -27,7 +29,9 @@ pub struct Cpu {
     pub regs: Registres,
     pub interrupts: Interrupts,
     pub ctx: Ctx,
-    pub exec_state: ExecutionState,
+    pub exec_state: ExecutionState, //命令
+    pub operand_state: ExecutionState, //オペランドの読み書き、スタック操作
+    pub imm_state: ExecutionState, //即値の読み出し
 }
 impl Cpu {
     pub fn fetch(&mut self, bus: &Peripherals) {
@@ -39,6 +43,7 @@ impl Cpu {
             self.ctx.int = false;
         }
         self.ctx.cb = false;
+        self.ctx.fetched = true;
     }
 
     pub fn decode(&mut self, bus: &mut Peripherals) {
@@ -583,8 +588,23 @@ impl Cpu {
         });
     }
     
+    //mooneyeのテストROMは終了時にLD B,Bを実行する
+    pub fn is_ld_b_b(&self) -> bool {
+        self.ctx.opcode == 0x40 && !self.ctx.cb && !self.ctx.int
+    }
+
+    //命令の区切りに達したか
+    pub fn instruction_done(&self) -> bool {
+        self.ctx.fetched
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.ctx.opcode == 0x76 && !self.ctx.cb && self.exec_state.step == 1
+    }
+
     pub fn emulate_cycle(&mut self, bus: &mut Peripherals) {
         // println!("PC: {:#06x}", self.regs.pc);
+        self.ctx.fetched = false;
         if self.ctx.int {
             self.call_isr(bus);
         } else {
@@ -595,18 +615,72 @@ impl Cpu {
 
 #[cfg(test)]
 mod unit_test {
-    use crate::{bootrom::Bootrom, register::Registres};
+    use crate::bootrom::Bootrom;
+    use crate::cartridge::Cartridge;
+    use crate::header::unit_test::fix_header;
+    use crate::interruputs::{VBLANK, STAT, TIMER, SERIAL, JOYPAD};
     use super::*;
 
+    //programをブートROMとして0x0000から実行する
+    fn peripherals(program: &[u8]) -> Peripherals {
+        let mut bootrom = vec![0; 0x100];
+        bootrom[..program.len()].copy_from_slice(program);
+        let mut rom = vec![0; 0x8000];
+        fix_header(&mut rom);
+        Peripherals::new(Bootrom::new(bootrom), Cartridge::new(rom.into()).unwrap())
+    }
+
     #[test]
     fn test_fetch() {
-        let mut cpu = Cpu { regs:Registres::default(), ctx: Ctx::default() };
-        let mut peri = Peripherals::new(Bootrom::new(vec![0,0x00]));
+        let mut cpu = Cpu::default();
+        let peri = peripherals(&[0x00]);
         cpu.fetch(&peri);
         assert_eq!(0x00, cpu.ctx.opcode);
         assert_eq!(1, cpu.regs.pc);
         assert!(!cpu.ctx.cb);
     }
 
-    //decodexeいいテスト思い浮かばない
-}
\ No newline at end of file
+    #[test]
+    fn test_interrupt_vector() {
+        for (int, vector) in [(VBLANK, 0x40), (STAT, 0x48), (TIMER, 0x50), (SERIAL, 0x58), (JOYPAD, 0x60)] {
+            let mut cpu = Cpu::default();
+            let mut peri = peripherals(&[]);
+            cpu.regs.sp = 0xD000;
+            cpu.interrupts.ime = true;
+            cpu.interrupts.int_enable = 0x1F;
+            cpu.interrupts.int_flags = int;
+            //NOPの後に割り込みが入り、ISRの先頭をフェッチしたところで止める
+            for _ in 0..10 {
+                cpu.emulate_cycle(&mut peri);
+                if !cpu.interrupts.ime {
+                    break;
+                }
+            }
+            assert_eq!(vector + 1, cpu.regs.pc);
+            assert_eq!(0, cpu.interrupts.int_flags);
+        }
+    }
+
+    #[test]
+    fn test_nested_operand() {
+        let mut cpu = Cpu::default();
+        let mut peri = peripherals(&[
+            0x21, 0x00, 0xC0, //LD HL,0xC000
+            0x36, 0x42, //LD (HL),0x42
+            0xEA, 0x01, 0xC0, //LD (0xC001),A
+            0xCD, 0x20, 0x00, //CALL 0x0020
+        ]);
+        cpu.regs.a = 0x99;
+        cpu.regs.sp = 0xD000;
+        //CALLが終わるまで実行する 0x0020はNOPが続く
+        for _ in 0..24 {
+            cpu.emulate_cycle(&mut peri);
+        }
+        assert_eq!(0x42, peri.read(&cpu.interrupts, 0xC000));
+        assert_eq!(0x99, peri.read(&cpu.interrupts, 0xC001));
+        assert_eq!(0xCFFE, cpu.regs.sp);
+        assert_eq!(0x0B, peri.read(&cpu.interrupts, 0xCFFE));
+        assert_eq!(0x00, peri.read(&cpu.interrupts, 0xCFFF));
+        assert!((0x21..0x30).contains(&cpu.regs.pc));
+    }
+}
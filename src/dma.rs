@@ -0,0 +1,100 @@
+//OAM DMA 0xFF46に書き込んだ値XXとしてXX00~XX9Fの160バイトをOAMにコピーする
+//1マシンサイクルの準備の後、1サイクルに1バイトずつ転送する
+pub struct Dma {
+    source: u8, //0xFF46
+    delay: u8, //転送開始までの残りサイクル
+    index: u16, //次に転送するバイト
+    transferring: bool, //転送中はCPUからバスが見えなくなる
+    pub bus_value: u8, //最後に転送したバイト
+}
+impl Dma {
+    pub fn new() -> Self {
+        Self {
+            source: 0xFF,
+            delay: 0,
+            index: 0xA0,
+            transferring: false,
+            bus_value: 0xFF,
+        }
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF46 => self.source,
+            _ => unreachable!(),
+        }
+    }
+
+    //転送中に書き込むと最初からやり直す(準備の間も前の転送が止まるまでバスは塞がったまま)
+    pub fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xFF46 => {
+                self.source = val;
+                self.delay = 1;
+                self.index = 0;
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn is_transferring(&self) -> bool {
+        self.transferring
+    }
+
+    //このサイクルで転送する(コピー元アドレス, OAMのオフセット)を返す
+    pub fn emulate_cycle(&mut self) -> Option<(u16, usize)> {
+        if self.delay > 0 {
+            self.delay -= 1;
+            return None;
+        }
+        if self.index >= 0xA0 {
+            self.transferring = false;
+            return None;
+        }
+        self.transferring = true;
+        let mut addr = ((self.source as u16) << 8) | self.index;
+        //0xE000以降はWRAMのミラーとして読む
+        if addr >= 0xE000 {
+            addr -= 0x2000;
+        }
+        let ret = (addr, self.index as usize);
+        self.index += 1;
+        Some(ret)
+    }
+}
+
+#[cfg(test)]
+mod unit_test {
+    use super::Dma;
+
+    #[test]
+    fn test_transfer() {
+        let mut dma = Dma::new();
+        assert_eq!(None, dma.emulate_cycle());
+        dma.write(0xFF46, 0xC1);
+        assert_eq!(0xC1, dma.read(0xFF46));
+        //準備の1サイクル
+        assert_eq!(None, dma.emulate_cycle());
+        assert!(!dma.is_transferring());
+        for i in 0..0xA0 {
+            assert_eq!(Some((0xC100 + i as u16, i)), dma.emulate_cycle());
+            assert!(dma.is_transferring());
+        }
+        assert_eq!(None, dma.emulate_cycle());
+        assert!(!dma.is_transferring());
+    }
+
+    #[test]
+    fn test_restart() {
+        let mut dma = Dma::new();
+        dma.write(0xFF46, 0xC0);
+        for _ in 0..10 {
+            dma.emulate_cycle();
+        }
+        dma.write(0xFF46, 0xFE);
+        assert_eq!(None, dma.emulate_cycle());
+        //やり直しの準備中もバスは塞がっている
+        assert!(dma.is_transferring());
+        assert_eq!(Some((0xDE00, 0)), dma.emulate_cycle());
+    }
+}
@@ -0,0 +1,204 @@
+use std::thread;
+use std::time::{Duration, Instant};
+
+use sdl2::EventPump;
+use sdl2::event::Event;
+use sdl2::keyboard::{Keycode, Mod};
+
+use crate::audio::Audio;
+use crate::gbs::Gbs;
+use crate::gameboy::{GameBoy, Channel, CPU_CLOCK_HZ, M_CYCLE_CLOCK, FRAME_CYCLES};
+use crate::joypad::Button;
+use crate::lcd::LCD;
+use crate::palette::Palette;
+use crate::save::SaveFile;
+
+//1フレームの実時間 約16.74ms
+const FRAME_NANOS: u128 = FRAME_CYCLES as u128 * M_CYCLE_CLOCK * 1_000_000_000 / CPU_CLOCK_HZ;
+
+#[derive(Clone)]
+pub struct FrontendConfig {
+    pub scale: u32,
+    pub palette: Palette,
+    pub fast_forward: bool, //実機の速さに合わせず全速力で動かす
+    pub mute: bool,
+}
+impl Default for FrontendConfig {
+    fn default() -> Self {
+        Self {
+            scale: 4,
+            palette: Palette::default(),
+            fast_forward: false,
+            mute: false,
+        }
+    }
+}
+
+//SDLのウィンドウと入力でGameBoyを動かす
+pub struct SdlFrontend {
+    config: FrontendConfig,
+    lcd: LCD,
+    audio: Option<Audio>,
+    event_pump: EventPump,
+    show_scope: bool, //画面の代わりにチャンネルごとの波形を表示する
+    gbs_mode: bool, //左右キーで曲を切り替える
+    song_delta: i32, //次に再生する曲までの差
+    save: Option<SaveFile>,
+}
+impl SdlFrontend {
+    pub fn new(config: FrontendConfig) -> Result<Self, String> {
+        let sdl = sdl2::init()?;
+        let lcd = LCD::new(&sdl, config.scale)?;
+        //音が出せなくてもゲームは遊べるようにする
+        let audio = if config.mute {
+            None
+        } else {
+            Audio::new(&sdl)
+                .map_err(|e| eprintln!("warning: audio disabled: {}", e))
+                .ok()
+        };
+        let event_pump = sdl.event_pump()?;
+        Ok(Self {
+            config,
+            lcd,
+            audio,
+            event_pump,
+            show_scope: false,
+            gbs_mode: false,
+            song_delta: 0,
+            save: None,
+        })
+    }
+
+    //runの間に定期的に保存し、終わるときにも保存する
+    pub fn set_save_file(&mut self, save: SaveFile) {
+        self.save = Some(save);
+    }
+
+    //音声デバイスのサンプルレート 音が出せないときはNone
+    pub fn sample_rate(&self) -> Option<u32> {
+        self.audio.as_ref().map(|audio| audio.freq())
+    }
+
+    //ウィンドウが閉じられるまで実機と同じ速さで動かす
+    //音を出しているときは再生キューの減り具合で、ミュート中は時計で速さを合わせる
+    pub fn run(&mut self, gb: &mut GameBoy) {
+        let frame = Duration::from_nanos(FRAME_NANOS as u64);
+        if let Some(rate) = self.sample_rate() && gb.sample_rate() != rate {
+            gb.set_sample_rate(rate);
+        }
+        let mut next = Instant::now();
+        while self.poll_events(gb) {
+            gb.run_frame();
+            let pixels = if self.show_scope {
+                &gb.render_oscilloscope()[..]
+            } else {
+                gb.framebuffer()
+            };
+            self.lcd.draw(&self.config.palette.to_rgb(pixels));
+            if let Some(save) = &mut self.save && let Err(e) = save.autosave(gb) {
+                eprintln!("warning: cannot write {}: {}", save.path().display(), e);
+            }
+            let samples = gb.drain_samples();
+            if let Some(audio) = &mut self.audio {
+                audio.push(&samples, gb.sample_rate());
+                if !self.config.fast_forward {
+                    audio.wait();
+                }
+                continue;
+            }
+            if self.config.fast_forward {
+                continue;
+            }
+            next += frame;
+            let now = Instant::now();
+            if next > now {
+                thread::sleep(next - now);
+            } else {
+                //追いつけないときは遅れを持ち越さない
+                next = now;
+            }
+        }
+        if let Some(save) = &mut self.save && let Err(e) = save.save(gb) {
+            eprintln!("warning: cannot write {}: {}", save.path().display(), e);
+        }
+    }
+
+    //GBSのsong番目(0始まり)から再生する 左右キーで前後の曲に切り替え、画面には波形を表示する
+    pub fn run_gbs(&mut self, gb: &mut GameBoy, gbs: &Gbs, mut song: u8) {
+        self.gbs_mode = true;
+        self.show_scope = true;
+        loop {
+            eprintln!("playing {} ({}/{})", gbs.title, song + 1, gbs.song_count);
+            self.song_delta = 0;
+            self.run(gb);
+            if self.song_delta == 0 {
+                return;
+            }
+            song = (song as i32 + self.song_delta).rem_euclid(gbs.song_count as i32) as u8;
+            gb.play_gbs_song(gbs, song);
+        }
+    }
+
+    //SDLのイベントを処理して終了要求か曲の切り替えがあればfalseを返す
+    //1~4でチャンネルのミュート、Shift+1~4でソロを切り替え、0で全て解除、Tabで波形表示を切り替える
+    fn poll_events(&mut self, gb: &mut GameBoy) -> bool {
+        let mut state = gb.joypad();
+        for event in self.event_pump.poll_iter() {
+            match event {
+                Event::Quit { .. } |
+                Event::KeyDown { keycode: Some(Keycode::Escape), .. } => return false,
+                Event::KeyDown { keycode: Some(Keycode::Left), .. } if self.gbs_mode => self.song_delta -= 1,
+                Event::KeyDown { keycode: Some(Keycode::Right), .. } if self.gbs_mode => self.song_delta += 1,
+                Event::KeyDown { keycode: Some(Keycode::Tab), repeat: false, .. } => {
+                    self.show_scope = !self.show_scope;
+                },
+                Event::KeyDown { keycode: Some(Keycode::Num0), repeat: false, .. } => for ch in Channel::ALL {
+                    gb.set_channel_muted(ch, false);
+                    gb.set_channel_solo(ch, false);
+                },
+                Event::KeyDown { keycode: Some(key), keymod, repeat: false, .. } if key2channel(key).is_some() => {
+                    let ch = key2channel(key).unwrap();
+                    if keymod.intersects(Mod::LSHIFTMOD | Mod::RSHIFTMOD) {
+                        gb.set_channel_solo(ch, !gb.is_channel_solo(ch));
+                    } else {
+                        gb.set_channel_muted(ch, !gb.is_channel_muted(ch));
+                    }
+                },
+                Event::KeyDown { keycode: Some(key), .. } => if let Some(button) = key2button(key) {
+                    state.set(button, true);
+                },
+                Event::KeyUp { keycode: Some(key), .. } => if let Some(button) = key2button(key) {
+                    state.set(button, false);
+                },
+                _ => {},
+            }
+        }
+        gb.set_joypad(state);
+        self.song_delta == 0
+    }
+}
+
+fn key2button(key: Keycode) -> Option<Button> {
+    match key {
+        Keycode::Right => Some(Button::Right),
+        Keycode::Left => Some(Button::Left),
+        Keycode::Up => Some(Button::Up),
+        Keycode::Down => Some(Button::Down),
+        Keycode::X => Some(Button::A),
+        Keycode::Z => Some(Button::B),
+        Keycode::Backspace => Some(Button::Select),
+        Keycode::Return => Some(Button::Start),
+        _ => None,
+    }
+}
+
+fn key2channel(key: Keycode) -> Option<Channel> {
+    match key {
+        Keycode::Num1 => Some(Channel::Pulse1),
+        Keycode::Num2 => Some(Channel::Pulse2),
+        Keycode::Num3 => Some(Channel::Wave),
+        Keycode::Num4 => Some(Channel::Noise),
+        _ => None,
+    }
+}
@@ -1,53 +1,297 @@
-use std::time;
 pub const CPU_CLOCK_HZ: u128 = 4_194_304;//一秒間に4194304クロック
 pub const M_CYCLE_CLOCK: u128 = 4;//gbマシンサイクルが4クロック
-const M_CYCLE_NANOS: u128 = M_CYCLE_CLOCK * 1_000_000_000 / CPU_CLOCK_HZ;//1マシンサイクル
+pub const FRAME_CYCLES: u32 = 17556;//1フレーム(154ライン x 114マシンサイクル)
 
+pub use crate::ppu::{LCD_WIDTH, LCD_HEIGHT, LCD_PIXELS};
+pub use crate::apu::{Channel, SAMPLE_RATE};
+
+use std::io;
+use std::path::Path;
+
+use crate::apu::Apu;
 use crate::cartridge::Cartridge;
 use crate::cpu::Cpu;
+use crate::gbs::Gbs;
 use crate::peripherals::Peripherals;
-use crate::lcd::LCD;
 use crate::bootrom::Bootrom;
+use crate::joypad::JoypadState;
+use crate::serial::LinkPort;
+
+//ブートROM無しで起動したときのレジスタの初期値が機種で異なる
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Model {
+    #[default]
+    Dmg,
+    Mgb, //ゲームボーイポケット
+}
+impl Model {
+    pub const NAMES: [&'static str; 2] = ["dmg", "mgb"];
 
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "dmg" => Some(Self::Dmg),
+            "mgb" => Some(Self::Mgb),
+            _ => None,
+        }
+    }
+}
+
+//エミュレーションの状態だけを持つ 表示や入力はフロントエンド側で行う
 pub struct GameBoy {
-    cpu: Cpu,
-    peripherals: Peripherals,
-    lcd: LCD,
+    pub(crate) cpu: Cpu,
+    pub(crate) peripherals: Peripherals,
 }
 impl GameBoy {
     pub fn new(bootrom: Bootrom, cartridge: Cartridge) -> Self {
-        let sdl = sdl2::init().expect("failed to initialize SDL");
-        let lcd = LCD::new(&sdl, 4);
-        let peripherals = Peripherals::new(bootrom, cartridge);
-        let cpu = Cpu::default();
+        Self::with_model(bootrom, cartridge, Model::Dmg)
+    }
+
+    pub fn with_model(bootrom: Bootrom, cartridge: Cartridge, model: Model) -> Self {
+        let skip_boot = !bootrom.is_active();
+        let mut peripherals = Peripherals::new(bootrom, cartridge);
+        let mut cpu = Cpu::default();
+        if skip_boot {
+            init_post_boot(&mut cpu, &mut peripherals, model);
+        }
         Self {
             cpu,
             peripherals,
-            lcd,
         }
     }
 
-    pub fn run(&mut self) {
-        let time = time::Instant::now();
-        let mut elapsed = 0;
+    //GBSのsong番目(0始まり)の曲を再生する INITを呼んだ後、VBlankかタイマー割り込みでPLAYを呼び続ける
+    pub fn from_gbs(gbs: &Gbs, song: u8) -> Self {
+        let mut gb = Self::with_model(Bootrom::disabled(), Cartridge::from_gbs(gbs), Model::Dmg);
+        gb.cpu.regs.a = song;
+        gb.cpu.regs.sp = gbs.stack_pointer;
+        gb
+    }
+
+    //最初からGBSの別の曲を再生する
+    //APUの出力の設定(サンプルレート、録音、VGMの記録、ミュートやソロ)はそのまま引き継ぐ
+    pub fn play_gbs_song(&mut self, gbs: &Gbs, song: u8) {
+        let apu = std::mem::replace(&mut self.peripherals.apu, Apu::new());
+        *self = Self::from_gbs(gbs, song);
+        self.peripherals.apu = apu;
+        //電源を入れ直してレジスタを初期化する
+        self.peripherals.write(&mut self.cpu.interrupts, 0xFF26, 0x00);
+        self.peripherals.write(&mut self.cpu.interrupts, 0xFF26, 0x80);
+        self.peripherals.write(&mut self.cpu.interrupts, 0xFF24, 0x77);
+        self.peripherals.write(&mut self.cpu.interrupts, 0xFF25, 0xF3);
+    }
+
+    pub fn joypad(&self) -> JoypadState {
+        self.peripherals.joypad.state()
+    }
+
+    pub fn set_joypad(&mut self, state: JoypadState) {
+        self.peripherals.joypad.set_state(&mut self.cpu.interrupts, state);
+    }
+
+    //振動カートリッジのモーターが回っているか フロントエンドでゲームパッドの振動などに使う
+    pub fn is_rumbling(&self) -> bool {
+        self.peripherals.cartridge.rumble()
+    }
+
+    //傾きセンサー付きのカートリッジにゲームパッドなどの傾き(-1.0~1.0)を伝える
+    pub fn set_tilt(&mut self, x: f32, y: f32) {
+        self.peripherals.cartridge.set_tilt(x, y);
+    }
+
+    //電池でバックアップされるカートリッジか 違えばセーブデータは要らない
+    pub fn has_battery(&self) -> bool {
+        self.peripherals.cartridge.has_battery()
+    }
+
+    //最後にsave_dataで取り出してからSRAMが書き換えられたか
+    pub fn is_save_dirty(&self) -> bool {
+        self.peripherals.cartridge.is_sram_dirty()
+    }
+
+    //SRAM(とRTC)の中身を.savファイルの形式で取り出す
+    pub fn save_data(&mut self) -> Vec<u8> {
+        self.peripherals.cartridge.save_data()
+    }
+
+    pub fn load_save_data(&mut self, data: &[u8]) {
+        self.peripherals.cartridge.load_save_data(data);
+    }
+
+    pub fn set_link_port(&mut self, link: Box<dyn LinkPort>) {
+        self.peripherals.serial.set_link(link);
+    }
+
+    //1マシンサイクル進める フレームを描き終えたらtrue
+    pub(crate) fn emulate_cycle(&mut self) -> bool {
+        self.cpu.emulate_cycle(&mut self.peripherals);
+        self.peripherals.emulate_dma(&self.cpu.interrupts);
+        let frame = self.peripherals.ppu.emulate_cycle(&mut self.cpu.interrupts);
+        self.peripherals.timer.emulate_cycle(&mut self.cpu.interrupts);
+        self.peripherals.serial.emulate_cycle(&mut self.cpu.interrupts);
+        let div = self.peripherals.timer.div_counter();
+        self.peripherals.apu.emulate_cycle(div);
+        frame
+    }
+
+    //次の命令の区切りまで進めて、かかったマシンサイクル数を返す HALT中は1サイクルで返る
+    pub fn step_instruction(&mut self) -> u32 {
+        let mut cycles = 0;
         loop {
+            self.emulate_cycle();
+            cycles += 1;
+            if self.cpu.instruction_done() || self.cpu.is_halted() {
+                return cycles;
+            }
+        }
+    }
 
-            // let lcdc_val = self.peripherals.read(&mut self.cpu.interrupts,0xFF40);
-            // let ly_val = self.peripherals.read(&mut self.cpu.interrupts, 0xFF44);
-            // if ly_val & 10 == 0 {
-            //     println!("LCDC: {:#04X}, LY: {}", lcdc_val, ly_val);
-            // }
-
-            let e = time.elapsed().as_nanos();
-            for _ in 0..(e - elapsed) / M_CYCLE_NANOS {
-                self.cpu.emulate_cycle(&mut self.peripherals);
-                if self.peripherals.ppu.emulate_cycle() {
-                    self.lcd.draw(self.peripherals.ppu.pixel_buffer());
-                }
-                // println!("{}", elapsed);
-                elapsed += M_CYCLE_NANOS;
+    //1フレーム分進める LCDがオフのときも1フレーム分の時間で返る
+    pub fn run_frame(&mut self) {
+        for _ in 0..FRAME_CYCLES {
+            if self.emulate_cycle() {
+                return;
             }
         }
     }
 
+    //APUが出力したサンプルを取り出す(L, Rの順、sample_rate() Hz)
+    pub fn drain_samples(&mut self) -> Vec<f32> {
+        self.peripherals.apu.drain_samples()
+    }
+
+    //出力サンプルレート 既定はSAMPLE_RATE
+    pub fn sample_rate(&self) -> u32 {
+        self.peripherals.apu.sample_rate()
+    }
+
+    //録音するときは始める前に変えておくこと
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.peripherals.apu.set_sample_rate(sample_rate);
+    }
+
+    //ミュートしたチャンネルは出力(録音も含む)に混ぜない
+    pub fn set_channel_muted(&mut self, ch: Channel, muted: bool) {
+        self.peripherals.apu.set_muted(ch, muted);
+    }
+
+    pub fn is_channel_muted(&self, ch: Channel) -> bool {
+        self.peripherals.apu.is_muted(ch)
+    }
+
+    //ソロのチャンネルがあるときはソロのチャンネルだけを出力する
+    pub fn set_channel_solo(&mut self, ch: Channel, solo: bool) {
+        self.peripherals.apu.set_solo(ch, solo);
+    }
+
+    pub fn is_channel_solo(&self, ch: Channel) -> bool {
+        self.peripherals.apu.is_solo(ch)
+    }
+
+    //チャンネルごとの波形をframebufferと同じ大きさ・形式で描く 上からチャンネル1~4
+    //ミュートなどで聞こえていないチャンネルは薄く描かれる
+    pub fn render_oscilloscope(&self) -> Vec<u8> {
+        self.peripherals.apu.render_scope()
+    }
+
+    //これ以降APUが出力する全サンプルをpathのWAVファイル(16bitステレオ、sample_rate() Hz)に書き出す
+    //drain_samplesで取り出すかどうかに関係なく記録される
+    pub fn start_audio_recording(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.peripherals.apu.start_recording(path.as_ref())
+    }
+
+    pub fn is_recording_audio(&self) -> bool {
+        self.peripherals.apu.is_recording()
+    }
+
+    //録音を終えてWAVファイルを閉じる
+    pub fn stop_audio_recording(&mut self) -> io::Result<()> {
+        self.peripherals.apu.stop_recording()
+    }
+
+    //これ以降のAPUのレジスタ(0xFF10~0xFF3F)への書き込みを、時刻と一緒にpathへVGM形式で記録する
+    //ファイルはstop_vgm_logで書き出される
+    pub fn start_vgm_log(&mut self, path: impl AsRef<Path>) -> io::Result<()> {
+        self.peripherals.apu.start_vgm(path.as_ref())
+    }
+
+    pub fn is_logging_vgm(&self) -> bool {
+        self.peripherals.apu.is_logging_vgm()
+    }
+
+    pub fn stop_vgm_log(&mut self) -> io::Result<()> {
+        self.peripherals.apu.stop_vgm()
+    }
+
+    //LCD_WIDTH x LCD_HEIGHTの画素ごとの明るさ(0x00~0xFF)
+    pub fn framebuffer(&self) -> &[u8] {
+        &self.peripherals.ppu.buffer[..LCD_PIXELS]
+    }
+}
+
+//ブートROMを実行し終えた直後のDMGの状態にする
+fn init_post_boot(cpu: &mut Cpu, peripherals: &mut Peripherals, model: Model) {
+    cpu.regs.write_af(match model {
+        Model::Dmg => 0x01B0,
+        Model::Mgb => 0xFFB0,
+    });
+    cpu.regs.write_bc(0x0013);
+    cpu.regs.write_de(0x00D8);
+    cpu.regs.write_hl(0x014D);
+    cpu.regs.sp = 0xFFFE;
+    cpu.regs.pc = 0x0100;
+    peripherals.write(&mut cpu.interrupts, 0xFF40, 0x91);
+    peripherals.write(&mut cpu.interrupts, 0xFF47, 0xFC);
+    peripherals.write(&mut cpu.interrupts, 0xFF26, 0x80);
+    peripherals.write(&mut cpu.interrupts, 0xFF24, 0x77);
+    peripherals.write(&mut cpu.interrupts, 0xFF25, 0xF3);
+    //DIV=0xAB 下位8bitもブートROMが終わった時点の位相に合わせる
+    peripherals.timer.set_div_counter(0xABCC);
+}
+
+#[cfg(test)]
+mod unit_test {
+    use super::GameBoy;
+    use crate::bootrom::Bootrom;
+    use crate::cartridge::Cartridge;
+    use crate::cartridge::unit_test::rom;
+    use crate::gbs::Gbs;
+    use crate::gbs::unit_test::gbs_file;
+
+    fn run_frames(gb: &mut GameBoy, frames: u32) {
+        for _ in 0..frames {
+            gb.run_frame();
+        }
+    }
+
+    #[test]
+    fn test_gbs_vblank() {
+        let gbs = Gbs::parse(&gbs_file(0x00)).unwrap();
+        let mut gb = GameBoy::from_gbs(&gbs, 2);
+        run_frames(&mut gb, 10);
+        //INITには曲番号が渡り、PLAYはVBlankごとに呼ばれる
+        assert_eq!(2, gb.peripherals.read(&gb.cpu.interrupts, 0xC000));
+        let count = gb.peripherals.read(&gb.cpu.interrupts, 0xC001);
+        assert!((9..=10).contains(&count), "{}", count);
+
+        gb.play_gbs_song(&gbs, 1);
+        run_frames(&mut gb, 1);
+        assert_eq!(1, gb.peripherals.read(&gb.cpu.interrupts, 0xC000));
+    }
+
+    #[test]
+    fn test_gbs_timer() {
+        //4096Hz / 256 = 16Hz
+        let gbs = Gbs::parse(&gbs_file(0x04)).unwrap();
+        let mut gb = GameBoy::from_gbs(&gbs, 0);
+        run_frames(&mut gb, 60);
+        let count = gb.peripherals.read(&gb.cpu.interrupts, 0xC001);
+        assert!((15..=16).contains(&count), "{}", count);
+    }
+
+    #[test]
+    fn test_post_boot_div() {
+        let gb = GameBoy::new(Bootrom::disabled(), Cartridge::new(rom(0x00, 0x00, 0x00)).unwrap());
+        assert_eq!(0xAB, gb.peripherals.read(&gb.cpu.interrupts, 0xFF04));
+        assert_eq!(0xABCC, gb.peripherals.timer.div_counter());
+    }
 }
@@ -0,0 +1,168 @@
+//GBS(Game Boy Sound System)形式の音楽データ
+//0x70バイトのヘッダの後に、load_addrから配置するデータが続く
+pub const HEADER_SIZE: usize = 0x70;
+//TAC(ヘッダ0x0F)のbit2が立っていればタイマー割り込み、でなければVBlankでPLAYを呼ぶ
+const TAC_TIMER: u8 = 1 << 2;
+
+//ヘッダより前の領域に置く再生用のコードの位置
+const DRIVER_ADDR: u16 = 0x0100;
+
+pub struct Gbs {
+    pub song_count: u8,
+    pub first_song: u8, //1始まり
+    pub load_addr: u16,
+    pub init_addr: u16,
+    pub play_addr: u16,
+    pub stack_pointer: u16,
+    pub tma: u8,
+    pub tac: u8,
+    pub title: String,
+    pub author: String,
+    pub copyright: String,
+    data: Vec<u8>,
+}
+impl Gbs {
+    pub fn parse(file: &[u8]) -> Result<Self, String> {
+        if file.len() < HEADER_SIZE || &file[0x00..0x03] != b"GBS" {
+            return Err("not a GBS file".to_string());
+        }
+        if file[0x03] != 1 {
+            return Err(format!("unsupported GBS version {}", file[0x03]));
+        }
+        let word = |i: usize| u16::from_le_bytes([file[i], file[i + 1]]);
+        let text = |i: usize| {
+            let field = &file[i..i + 0x20];
+            let len = field.iter().position(|&c| c == 0).unwrap_or(field.len());
+            String::from_utf8_lossy(&field[..len]).to_string()
+        };
+        let gbs = Self {
+            song_count: file[0x04],
+            first_song: file[0x05],
+            load_addr: word(0x06),
+            init_addr: word(0x08),
+            play_addr: word(0x0A),
+            stack_pointer: word(0x0C),
+            tma: file[0x0E],
+            tac: file[0x0F],
+            title: text(0x10),
+            author: text(0x30),
+            copyright: text(0x50),
+            data: file[HEADER_SIZE..].to_vec(),
+        };
+        if gbs.song_count == 0 {
+            return Err("GBS has no songs".to_string());
+        }
+        //再生用のコードとRSTの飛び先を置く場所が要る
+        if gbs.load_addr < 0x0400 || gbs.load_addr >= 0x8000 {
+            return Err(format!("invalid GBS load address {:04x}", gbs.load_addr));
+        }
+        Ok(gbs)
+    }
+
+    //最初に再生する曲(0始まり)
+    pub fn default_song(&self) -> u8 {
+        if (1..=self.song_count).contains(&self.first_song) {
+            self.first_song - 1
+        } else {
+            0
+        }
+    }
+
+    pub fn uses_timer(&self) -> bool {
+        self.tac & TAC_TIMER > 0
+    }
+
+    //load_addrにデータを置き、その前に再生用のコードを置いたROMイメージを作る
+    //大きさはバンク切り替えのマスクが効くように2の累乗にする
+    pub(crate) fn rom_image(&self) -> Box<[u8]> {
+        let size = (self.load_addr as usize + self.data.len()).next_power_of_two().max(0x8000);
+        let mut rom = vec![0xFF; size];
+        rom[self.load_addr as usize..self.load_addr as usize + self.data.len()].copy_from_slice(&self.data);
+
+        //RSTはload_addrからの相対位置に飛ぶ
+        for vector in (0x00..0x40).step_by(8) {
+            Self::put(&mut rom, vector, &Self::jp(self.load_addr + vector));
+        }
+        //割り込みでPLAYを呼ぶ
+        let handler = [0xCD, self.play_addr as u8, (self.play_addr >> 8) as u8, 0xD9]; //CALL play; RETI
+        Self::put(&mut rom, 0x40, &handler);
+        Self::put(&mut rom, 0x50, &handler);
+
+        //AとSPはGameBoy側で設定してからDRIVER_ADDRから実行する
+        let ie = if self.uses_timer() { 0x04 } else { 0x01 };
+        let driver = [
+            0xCD, self.init_addr as u8, (self.init_addr >> 8) as u8, //CALL init
+            0x3E, self.tma, 0xE0, 0x06, //LD A, tma; LDH (TMA), A
+            0x3E, self.tac, 0xE0, 0x07, //LD A, tac; LDH (TAC), A
+            0xAF, 0xE0, 0x0F, //XOR A; LDH (IF), A
+            0x3E, ie, 0xE0, 0xFF, //LD A, ie; LDH (IE), A
+            0xFB, //EI
+            0x76, //HALT
+            0x18, 0xFD, //JR -3
+        ];
+        Self::put(&mut rom, DRIVER_ADDR, &driver);
+        rom.into_boxed_slice()
+    }
+
+    fn put(rom: &mut [u8], addr: u16, code: &[u8]) {
+        rom[addr as usize..addr as usize + code.len()].copy_from_slice(code);
+    }
+
+    fn jp(addr: u16) -> [u8; 3] {
+        [0xC3, addr as u8, (addr >> 8) as u8]
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod unit_test {
+    use super::{Gbs, HEADER_SIZE};
+
+    //load_addrが0x0400で、INITはAを0xC000に書き、PLAYは0xC001を数えるGBS
+    pub(crate) fn gbs_file(tac: u8) -> Vec<u8> {
+        let mut file = vec![0; HEADER_SIZE];
+        file[0x00..0x04].copy_from_slice(b"GBS\x01");
+        file[0x04] = 3;
+        file[0x05] = 2;
+        file[0x06..0x08].copy_from_slice(&0x0400u16.to_le_bytes());
+        file[0x08..0x0A].copy_from_slice(&0x0400u16.to_le_bytes());
+        file[0x0A..0x0C].copy_from_slice(&0x0410u16.to_le_bytes());
+        file[0x0C..0x0E].copy_from_slice(&0xDFFFu16.to_le_bytes());
+        file[0x0E] = 0x00;
+        file[0x0F] = tac;
+        file[0x10..0x14].copy_from_slice(b"Test");
+        let mut data = vec![0; 0x20];
+        //INIT: LD (0xC000), A; XOR A; LD (0xC001), A; RET
+        data[0x00..0x08].copy_from_slice(&[0xEA, 0x00, 0xC0, 0xAF, 0xEA, 0x01, 0xC0, 0xC9]);
+        //PLAY: LD HL, 0xC001; INC (HL); RET
+        data[0x10..0x15].copy_from_slice(&[0x21, 0x01, 0xC0, 0x34, 0xC9]);
+        file.extend(data);
+        file
+    }
+
+    #[test]
+    fn test_parse() {
+        let gbs = Gbs::parse(&gbs_file(0x00)).unwrap();
+        assert_eq!(3, gbs.song_count);
+        assert_eq!(1, gbs.default_song());
+        assert_eq!(0x0410, gbs.play_addr);
+        assert_eq!("Test", gbs.title);
+        assert!(!gbs.uses_timer());
+        assert!(Gbs::parse(b"GBX").is_err());
+        let mut file = gbs_file(0x00);
+        file[0x06] = 0x00;
+        file[0x07] = 0x01;
+        assert!(Gbs::parse(&file).is_err());
+    }
+
+    #[test]
+    fn test_rom_image() {
+        let gbs = Gbs::parse(&gbs_file(0x04)).unwrap();
+        let rom = gbs.rom_image();
+        assert_eq!(0x8000, rom.len());
+        //RST 0x38はload_addr + 0x38に飛ぶ
+        assert_eq!([0xC3, 0x38, 0x04], rom[0x38..0x3B]);
+        //タイマー割り込みでPLAYを呼ぶ
+        assert_eq!([0xCD, 0x10, 0x04, 0xD9], rom[0x50..0x54]);
+        assert_eq!(0xEA, rom[0x0400]);
+    }
+}
@@ -0,0 +1,288 @@
+use std::fmt;
+
+//ヘッダは0x100~0x14F
+pub const HEADER_END: usize = 0x150;
+
+//0x104~0x133のロゴ ブートROMはこれと一致しなければ止まる
+pub const NINTENDO_LOGO: [u8; 48] = [
+    0xCE, 0xED, 0x66, 0x66, 0xCC, 0x0D, 0x00, 0x0B, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0C, 0x00, 0x0D,
+    0x00, 0x08, 0x11, 0x1F, 0x88, 0x89, 0x00, 0x0E, 0xDC, 0xCC, 0x6E, 0xE6, 0xDD, 0xDD, 0xD9, 0x99,
+    0xBB, 0xBB, 0x67, 0x63, 0x6E, 0x0E, 0xEC, 0xCC, 0xDD, 0xDC, 0x99, 0x9F, 0xBB, 0xB9, 0x33, 0x3E,
+];
+
+#[derive(Debug, PartialEq)]
+pub enum HeaderError {
+    TooShort(usize), //ROMがヘッダの終わりまで無い
+    InvalidRomSize(u8),
+    InvalidSramSize(u8),
+    UnsupportedType(u8), //対応するマッパーが無い
+}
+impl fmt::Display for HeaderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::TooShort(len) => write!(f, "ROM is too short for a header ({} bytes)", len),
+            Self::InvalidRomSize(code) => write!(f, "invalid ROM size {:02x}", code),
+            Self::InvalidSramSize(code) => write!(f, "invalid SRAM size {:02x}", code),
+            Self::UnsupportedType(code) => write!(f, "unsupported cartridge type {:02x}", code),
+        }
+    }
+}
+impl std::error::Error for HeaderError {}
+
+//0x143
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CgbSupport {
+    None,
+    Compatible, //0x80 DMGでも動く
+    Only, //0xC0
+}
+
+//0x14A
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Destination {
+    Japan,
+    Overseas,
+    Unknown(u8),
+}
+
+//チェックサムやロゴの検証結果は持つだけで、合わなくてもエラーにはしない
+#[derive(Clone, Debug)]
+pub struct CartridgeHeader {
+    pub title: String,
+    pub manufacturer: Option<String>, //CGBのソフトの一部だけが持つ4文字のコード
+    pub cgb: CgbSupport,
+    pub sgb: bool,
+    pub cartridge_type: u8,
+    pub rom_size: usize,
+    pub sram_size: usize,
+    pub destination: Destination,
+    pub old_licensee: u8, //0x33ならnew_licenseeを使う
+    pub new_licensee: [u8; 2],
+    pub version: u8,
+    pub header_checksum: u8,
+    pub global_checksum: u16,
+    pub logo_valid: bool,
+    pub header_checksum_valid: bool,
+    pub global_checksum_valid: bool,
+}
+impl CartridgeHeader {
+    //ROM全体を受け取る 全体のチェックサムも確かめる
+    pub fn parse(rom: &[u8]) -> Result<Self, HeaderError> {
+        if rom.len() < HEADER_END {
+            return Err(HeaderError::TooShort(rom.len()));
+        }
+        let cgb = match rom[0x143] {
+            0x80 => CgbSupport::Compatible,
+            0xC0 => CgbSupport::Only,
+            _ => CgbSupport::None,
+        };
+        //CGBのソフトではタイトルの末尾がCGBフラグやメーカーコードに使われる
+        let code = &rom[0x13F..0x143];
+        let manufacturer = (cgb != CgbSupport::None
+            && code.iter().all(|c| c.is_ascii_uppercase() || c.is_ascii_digit()))
+            .then(|| String::from_utf8_lossy(code).to_string());
+        let title_end = match (cgb, &manufacturer) {
+            (_, Some(_)) => 0x13F,
+            (CgbSupport::None, _) => 0x144,
+            _ => 0x143,
+        };
+        let checksum = rom[0x134..=0x14C].iter().fold(0u8, |sum, &c| sum.wrapping_sub(c).wrapping_sub(1));
+        let global = rom.iter().enumerate()
+            .filter(|&(i, _)| i != 0x14E && i != 0x14F)
+            .fold(0u16, |sum, (_, &c)| sum.wrapping_add(c as u16));
+        let global_checksum = u16::from_be_bytes([rom[0x14E], rom[0x14F]]);
+        Ok(Self {
+            title: decode_title(&rom[0x134..title_end]),
+            manufacturer,
+            cgb,
+            sgb: rom[0x146] == 0x03,
+            cartridge_type: rom[0x147],
+            rom_size: rom_size(rom[0x148])?,
+            sram_size: sram_size(rom[0x149])?,
+            destination: match rom[0x14A] {
+                0x00 => Destination::Japan,
+                0x01 => Destination::Overseas,
+                code => Destination::Unknown(code),
+            },
+            old_licensee: rom[0x14B],
+            new_licensee: [rom[0x144], rom[0x145]],
+            version: rom[0x14C],
+            header_checksum: rom[0x14D],
+            global_checksum,
+            logo_valid: rom[0x104..0x134] == NINTENDO_LOGO,
+            header_checksum_valid: checksum == rom[0x14D],
+            global_checksum_valid: global == global_checksum,
+        })
+    }
+
+    //発売元の名前 0x33なら新しい2文字のコードから引く
+    pub fn licensee(&self) -> Option<&'static str> {
+        if self.old_licensee == 0x33 {
+            self.new_licensee_name()
+        } else {
+            self.old_licensee_name()
+        }
+    }
+
+    pub fn old_licensee_name(&self) -> Option<&'static str> {
+        OLD_LICENSEES.iter().find(|&&(code, _)| code == self.old_licensee).map(|&(_, name)| name)
+    }
+
+    pub fn new_licensee_name(&self) -> Option<&'static str> {
+        NEW_LICENSEES.iter().find(|&&(code, _)| *code == self.new_licensee).map(|&(_, name)| name)
+    }
+}
+
+//NULで終わる 表示できない文字は'?'にする
+fn decode_title(bytes: &[u8]) -> String {
+    let len = bytes.iter().position(|&c| c == 0).unwrap_or(bytes.len());
+    bytes[..len].iter()
+        .map(|&c| if c.is_ascii_graphic() || c == b' ' { c as char } else { '?' })
+        .collect::<String>()
+        .trim_end()
+        .to_string()
+}
+
+fn rom_size(code: u8) -> Result<usize, HeaderError> {
+    match code {
+        0x00..=0x08 => Ok(0x8000 << code),
+        //2の累乗でない大きさ
+        0x52 => Ok(72 * 0x4000),
+        0x53 => Ok(80 * 0x4000),
+        0x54 => Ok(96 * 0x4000),
+        _ => Err(HeaderError::InvalidRomSize(code)),
+    }
+}
+
+fn sram_size(code: u8) -> Result<usize, HeaderError> {
+    match code {
+        0x00 => Ok(0),
+        0x01 => Ok(0x800),
+        0x02 => Ok(0x2000),
+        0x03 => Ok(0x8000),
+        0x04 => Ok(0x20000),
+        0x05 => Ok(0x10000),
+        _ => Err(HeaderError::InvalidSramSize(code)),
+    }
+}
+
+const OLD_LICENSEES: &[(u8, &str)] = &[
+    (0x00, "None"), (0x01, "Nintendo"), (0x08, "Capcom"), (0x09, "HOT-B"), (0x0A, "Jaleco"),
+    (0x0B, "Coconuts Japan"), (0x0C, "Elite Systems"), (0x13, "EA (Electronic Arts)"), (0x18, "Hudson Soft"),
+    (0x19, "ITC Entertainment"), (0x1A, "Yanoman"), (0x1D, "Japan Clary"), (0x1F, "Virgin Games Ltd."),
+    (0x24, "PCM Complete"), (0x25, "San-X"), (0x28, "Kemco"), (0x29, "SETA Corporation"), (0x30, "Infogrames"),
+    (0x31, "Nintendo"), (0x32, "Bandai"), (0x34, "Konami"), (0x35, "HectorSoft"), (0x38, "Capcom"),
+    (0x39, "Banpresto"), (0x3C, "Entertainment Interactive"), (0x3E, "Gremlin"), (0x41, "Ubi Soft"),
+    (0x42, "Atlus"), (0x44, "Malibu Interactive"), (0x46, "Angel"), (0x47, "Spectrum HoloByte"), (0x49, "Irem"),
+    (0x4A, "Virgin Games Ltd."), (0x4D, "Malibu Interactive"), (0x4F, "U.S. Gold"), (0x50, "Absolute"),
+    (0x51, "Acclaim Entertainment"), (0x52, "Activision"), (0x53, "Sammy USA Corporation"), (0x54, "GameTek"),
+    (0x55, "Park Place"), (0x56, "LJN"), (0x57, "Matchbox"), (0x59, "Milton Bradley Company"),
+    (0x5A, "Mindscape"), (0x5B, "Romstar"), (0x5C, "Naxat Soft"), (0x5D, "Tradewest"),
+    (0x60, "Titus Interactive"), (0x61, "Virgin Games Ltd."), (0x67, "Ocean Software"),
+    (0x69, "EA (Electronic Arts)"), (0x6E, "Elite Systems"), (0x6F, "Electro Brain"), (0x70, "Infogrames"),
+    (0x71, "Interplay Entertainment"), (0x72, "Broderbund"), (0x73, "Sculptured Software"),
+    (0x75, "The Sales Curve Limited"), (0x78, "THQ"), (0x79, "Accolade"), (0x7A, "Triffix Entertainment"),
+    (0x7C, "MicroProse"), (0x7F, "Kemco"), (0x80, "Misawa Entertainment"), (0x83, "LOZC G."),
+    (0x86, "Tokuma Shoten"), (0x8B, "Bullet-Proof Software"), (0x8C, "Vic Tokai Corp."), (0x8E, "Ape Inc."),
+    (0x8F, "I'Max"), (0x91, "Chunsoft Co."), (0x92, "Video System"), (0x93, "Tsubaraya Productions"),
+    (0x95, "Varie"), (0x96, "Yonezawa/S'Pal"), (0x97, "Kemco"), (0x99, "Arc"), (0x9A, "Nihon Bussan"),
+    (0x9B, "Tecmo"), (0x9C, "Imagineer"), (0x9D, "Banpresto"), (0x9F, "Nova"), (0xA1, "Hori Electric"),
+    (0xA2, "Bandai"), (0xA4, "Konami"), (0xA6, "Kawada"), (0xA7, "Takara"), (0xA9, "Technos Japan"),
+    (0xAA, "Broderbund"), (0xAC, "Toei Animation"), (0xAD, "Toho"), (0xAF, "Namco"),
+    (0xB0, "Acclaim Entertainment"), (0xB1, "ASCII Corporation or Nexsoft"), (0xB2, "Bandai"),
+    (0xB4, "Square Enix"), (0xB6, "HAL Laboratory"), (0xB7, "SNK"), (0xB9, "Pony Canyon"),
+    (0xBA, "Culture Brain"), (0xBB, "Sunsoft"), (0xBD, "Sony Imagesoft"), (0xBF, "Sammy Corporation"),
+    (0xC0, "Taito"), (0xC2, "Kemco"), (0xC3, "Square"), (0xC4, "Tokuma Shoten"), (0xC5, "Data East"),
+    (0xC6, "Tonkin House"), (0xC8, "Koei"), (0xC9, "UFL"), (0xCA, "Ultra Games"), (0xCB, "VAP, Inc."),
+    (0xCC, "Use Corporation"), (0xCD, "Meldac"), (0xCE, "Pony Canyon"), (0xCF, "Angel"), (0xD0, "Taito"),
+    (0xD1, "SOFEL"), (0xD2, "Quest"), (0xD3, "Sigma Enterprises"), (0xD4, "ASK Kodansha Co."),
+    (0xD6, "Naxat Soft"), (0xD7, "Copya System"), (0xD9, "Banpresto"), (0xDA, "Tomy"), (0xDB, "LJN"),
+    (0xDD, "Nippon Computer Systems"), (0xDE, "Human Ent."), (0xDF, "Altron"), (0xE0, "Jaleco"),
+    (0xE1, "Towa Chiki"), (0xE2, "Yutaka"), (0xE3, "Varie"), (0xE5, "Epoch"), (0xE7, "Athena"),
+    (0xE8, "Asmik Ace Entertainment"), (0xE9, "Natsume"), (0xEA, "King Records"), (0xEB, "Atlus"),
+    (0xEC, "Epic/Sony Records"), (0xEE, "IGS"), (0xF0, "A Wave"), (0xF3, "Extreme Entertainment"),
+    (0xFF, "LJN"),
+];
+
+const NEW_LICENSEES: &[(&[u8; 2], &str)] = &[
+    (b"00", "None"), (b"01", "Nintendo Research & Development 1"), (b"08", "Capcom"),
+    (b"13", "EA (Electronic Arts)"), (b"18", "Hudson Soft"), (b"19", "B-AI"), (b"20", "KSS"),
+    (b"22", "Planning Office WADA"), (b"24", "PCM Complete"), (b"25", "San-X"), (b"28", "Kemco"),
+    (b"29", "SETA Corporation"), (b"30", "Viacom"), (b"31", "Nintendo"), (b"32", "Bandai"),
+    (b"33", "Ocean Software/Acclaim Entertainment"), (b"34", "Konami"), (b"35", "HectorSoft"),
+    (b"37", "Taito"), (b"38", "Hudson Soft"), (b"39", "Banpresto"), (b"41", "Ubi Soft"), (b"42", "Atlus"),
+    (b"44", "Malibu Interactive"), (b"46", "Angel"), (b"47", "Bullet-Proof Software"), (b"49", "Irem"),
+    (b"50", "Absolute"), (b"51", "Acclaim Entertainment"), (b"52", "Activision"),
+    (b"53", "Sammy USA Corporation"), (b"54", "Konami"), (b"55", "Hi Tech Expressions"), (b"56", "LJN"),
+    (b"57", "Matchbox"), (b"58", "Mattel"), (b"59", "Milton Bradley Company"), (b"60", "Titus Interactive"),
+    (b"61", "Virgin Games Ltd."), (b"64", "Lucasfilm Games"), (b"67", "Ocean Software"),
+    (b"69", "EA (Electronic Arts)"), (b"70", "Infogrames"), (b"71", "Interplay Entertainment"),
+    (b"72", "Broderbund"), (b"73", "Sculptured Software"), (b"75", "The Sales Curve Limited"), (b"78", "THQ"),
+    (b"79", "Accolade"), (b"80", "Misawa Entertainment"), (b"83", "LOZC G."), (b"86", "Tokuma Shoten"),
+    (b"87", "Tsukuda Original"), (b"91", "Chunsoft Co."), (b"92", "Video System"),
+    (b"93", "Ocean Software/Acclaim Entertainment"), (b"95", "Varie"), (b"96", "Yonezawa/S'Pal"),
+    (b"97", "Kaneko"), (b"99", "Pack-In-Video"), (b"9H", "Bottom Up"), (b"A4", "Konami (Yu-Gi-Oh!)"),
+    (b"BL", "MTO"), (b"DK", "Kodansha"),
+];
+
+#[cfg(test)]
+pub(crate) mod unit_test {
+    use super::{CartridgeHeader, CgbSupport, Destination, HeaderError, NINTENDO_LOGO};
+
+    //ロゴとヘッダのチェックサムを埋める
+    pub(crate) fn fix_header(rom: &mut [u8]) {
+        rom[0x104..0x134].copy_from_slice(&NINTENDO_LOGO);
+        rom[0x14D] = rom[0x134..=0x14C].iter().fold(0u8, |sum, &c| sum.wrapping_sub(c).wrapping_sub(1));
+    }
+
+    #[test]
+    fn test_parse() {
+        let mut rom = vec![0; 0x8000];
+        rom[0x134..0x143].copy_from_slice(b"POKEMON_GLDAAUE");
+        rom[0x143] = 0x80;
+        rom[0x144..0x146].copy_from_slice(b"01");
+        rom[0x146] = 0x03;
+        rom[0x147] = 0x10;
+        rom[0x148] = 0x06;
+        rom[0x149] = 0x03;
+        rom[0x14A] = 0x01;
+        rom[0x14B] = 0x33;
+        rom[0x14C] = 0x02;
+        fix_header(&mut rom);
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!("POKEMON_GLD", header.title);
+        assert_eq!(Some("AAUE".to_string()), header.manufacturer);
+        assert_eq!(CgbSupport::Compatible, header.cgb);
+        assert!(header.sgb);
+        assert_eq!(0x200000, header.rom_size);
+        assert_eq!(0x8000, header.sram_size);
+        assert_eq!(Destination::Overseas, header.destination);
+        assert_eq!(Some("Nintendo Research & Development 1"), header.licensee());
+        assert_eq!(2, header.version);
+        assert!(header.logo_valid && header.header_checksum_valid);
+        //全体のチェックサムは合っていなくてもエラーにならない
+        assert!(!header.global_checksum_valid);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut rom = vec![0; 0x8000];
+        //表示できない文字
+        rom[0x134..0x138].copy_from_slice(b"A\xFFB\x01");
+        rom[0x14B] = 0x01;
+        let sum = rom.iter().fold(0u16, |sum, &c| sum.wrapping_add(c as u16));
+        rom[0x14E..0x150].copy_from_slice(&sum.to_be_bytes());
+        let header = CartridgeHeader::parse(&rom).unwrap();
+        assert_eq!("A?B?", header.title);
+        assert_eq!(None, header.manufacturer);
+        assert_eq!(Some("Nintendo"), header.licensee());
+        assert!(!header.logo_valid && !header.header_checksum_valid);
+        assert!(header.global_checksum_valid);
+        assert_eq!(Err(HeaderError::TooShort(0x100)), CartridgeHeader::parse(&rom[..0x100]).map(|_| ()));
+        rom[0x148] = 0x09;
+        assert_eq!(Err(HeaderError::InvalidRomSize(0x09)), CartridgeHeader::parse(&rom).map(|_| ()));
+        rom[0x148] = 0x00;
+        rom[0x149] = 0x06;
+        assert_eq!(Err(HeaderError::InvalidSramSize(0x06)), CartridgeHeader::parse(&rom).map(|_| ()));
+    }
+}
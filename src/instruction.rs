@@ -681,27 +681,27 @@ impl Cpu {
     }
 
     pub fn push16(&mut self, bus: &mut Peripherals, val: u16) -> Option<()> {
-        step!(self, None, {
+        step!(self.operand_state, None, {
             0: {
-                go!(self, 1);
+                go!(self.operand_state, 1);
                 return None;
             },
             1: {
                 let [lo, hi] = u16::to_le_bytes(val);
                 self.regs.sp = self.regs.sp.wrapping_sub(1);
                 bus.write(&mut self.interrupts, self.regs.sp, hi);
-                self.exec_state.val8 = lo;
-                go!(self, 2);
+                self.operand_state.val8 = lo;
+                go!(self.operand_state, 2);
                 return None;
             },
             2: {
                 self.regs.sp = self.regs.sp.wrapping_sub(1);
-                bus.write(&mut self.interrupts, self.regs.sp, self.exec_state.val8);
-                go!(self, 3);
+                bus.write(&mut self.interrupts, self.regs.sp, self.operand_state.val8);
+                go!(self.operand_state, 3);
                 return None;
             },
             3: {
-                go!(self, 0);
+                go!(self.operand_state, 0);
                 return Some(()); 
             },
         });
@@ -724,23 +724,23 @@ impl Cpu {
     }
 
     pub fn pop16(&mut self, bus: &Peripherals) -> Option<u16> {
-        step!(self, None, {
+        step!(self.operand_state, None, {
             0: {
-                self.exec_state.val8 = bus.read(&mut self.interrupts, self.regs.sp);
+                self.operand_state.val8 = bus.read(&mut self.interrupts, self.regs.sp);
                 self.regs.sp = self.regs.sp.wrapping_add(1);
-                go!(self, 1);
+                go!(self.operand_state, 1);
                 return None;
             },
             1: {
                 let hi = bus.read(&mut self.interrupts, self.regs.sp);
                 self.regs.sp = self.regs.sp.wrapping_add(1);
-                self.exec_state.val16 = u16::from_le_bytes([self.exec_state.val8, hi]);
-                go!(self, 2);
+                self.operand_state.val16 = u16::from_le_bytes([self.operand_state.val8, hi]);
+                go!(self.operand_state, 2);
                 return None;
             },
             2: {
-                go!(self, 0);
-                return Some(self.exec_state.val16);
+                go!(self.operand_state, 0);
+                return Some(self.operand_state.val16);
             },
         });
     }
@@ -0,0 +1,144 @@
+use crate::interruputs::{self, Interrupts};
+
+//P1/JOYP Register
+const SELECT_ACTION: u8 = 1 << 5; //P15 0で選択
+const SELECT_DIRECTION: u8 = 1 << 4; //P14 0で選択
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Button { Right, Left, Up, Down, A, B, Select, Start }
+
+//フロントエンドに依存しないボタンの押下状態 trueで押されている
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct JoypadState {
+    pub right: bool,
+    pub left: bool,
+    pub up: bool,
+    pub down: bool,
+    pub a: bool,
+    pub b: bool,
+    pub select: bool,
+    pub start: bool,
+}
+impl JoypadState {
+    pub fn set(&mut self, button: Button, pressed: bool) {
+        match button {
+            Button::Right => self.right = pressed,
+            Button::Left => self.left = pressed,
+            Button::Up => self.up = pressed,
+            Button::Down => self.down = pressed,
+            Button::A => self.a = pressed,
+            Button::B => self.b = pressed,
+            Button::Select => self.select = pressed,
+            Button::Start => self.start = pressed,
+        }
+    }
+
+    //押されているボタンを1で返す(bit0から Right, Left, Up, Down)
+    fn directions(&self) -> u8 {
+        (self.right as u8) | (self.left as u8) << 1 | (self.up as u8) << 2 | (self.down as u8) << 3
+    }
+
+    //bit0から A, B, Select, Start
+    fn actions(&self) -> u8 {
+        (self.a as u8) | (self.b as u8) << 1 | (self.select as u8) << 2 | (self.start as u8) << 3
+    }
+}
+
+pub struct Joypad {
+    select: u8, //0xFF00 bit4,5
+    state: JoypadState,
+    lines: u8, //P10~P13の出力(0で押下)
+}
+impl Joypad {
+    pub fn new() -> Self {
+        Self {
+            select: SELECT_ACTION | SELECT_DIRECTION,
+            state: JoypadState::default(),
+            lines: 0x0F,
+        }
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF00 => 0xC0 | self.select | self.lines,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write(&mut self, interrupts: &mut Interrupts, addr: u16, val: u8) {
+        match addr {
+            0xFF00 => {
+                self.select = val & (SELECT_ACTION | SELECT_DIRECTION);
+                self.update_lines(interrupts);
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn state(&self) -> JoypadState {
+        self.state
+    }
+
+    pub fn set_state(&mut self, interrupts: &mut Interrupts, state: JoypadState) {
+        self.state = state;
+        self.update_lines(interrupts);
+    }
+
+    //P10~P13のどれかがHighからLowに落ちたらJOYPAD割り込み
+    fn update_lines(&mut self, interrupts: &mut Interrupts) {
+        let mut pressed = 0;
+        if self.select & SELECT_DIRECTION == 0 {
+            pressed |= self.state.directions();
+        }
+        if self.select & SELECT_ACTION == 0 {
+            pressed |= self.state.actions();
+        }
+        let lines = !pressed & 0x0F;
+        if self.lines & !lines > 0 {
+            interrupts.irq(interruputs::JOYPAD);
+        }
+        self.lines = lines;
+    }
+}
+impl Default for Joypad {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod unit_test {
+    use super::{Button, Joypad, JoypadState};
+    use crate::interruputs::{self, Interrupts};
+
+    #[test]
+    fn test_select_lines() {
+        let mut joypad = Joypad::new();
+        let mut interrupts = Interrupts::default();
+        let mut state = JoypadState::default();
+        state.set(Button::Down, true);
+        state.set(Button::A, true);
+        joypad.set_state(&mut interrupts, state);
+        assert_eq!(0xFF, joypad.read(0xFF00));
+        joypad.write(&mut interrupts, 0xFF00, 0x20);
+        assert_eq!(0xE7, joypad.read(0xFF00));
+        joypad.write(&mut interrupts, 0xFF00, 0x10);
+        assert_eq!(0xDE, joypad.read(0xFF00));
+    }
+
+    #[test]
+    fn test_interrupt() {
+        let mut joypad = Joypad::new();
+        let mut interrupts = Interrupts::default();
+        joypad.write(&mut interrupts, 0xFF00, 0x10);
+        assert_eq!(0, interrupts.int_flags & interruputs::JOYPAD);
+        let mut state = JoypadState::default();
+        state.set(Button::Start, true);
+        joypad.set_state(&mut interrupts, state);
+        assert!(interrupts.int_flags & interruputs::JOYPAD > 0);
+        //離したときは割り込みは起きない
+        interrupts.int_flags = 0;
+        joypad.set_state(&mut interrupts, JoypadState::default());
+        assert_eq!(0, interrupts.int_flags & interruputs::JOYPAD);
+    }
+}
@@ -8,27 +8,26 @@ use crate::ppu::LCD_HEIGHT;
 
 pub struct LCD(Canvas<Window>);
 impl LCD {
-    pub fn new(sdl: &Sdl, scale: u32) -> LCD {
+    pub fn new(sdl: &Sdl, scale: u32) -> Result<LCD, String> {
         let window = sdl
-            .video()
-            .expect("failed to initialize SDL video subsystem")
-            .window(" gb-emu test", 800, 600)
+            .video()?
+            .window("gbemu-rust", LCD_WIDTH as u32 * scale, LCD_HEIGHT as u32 * scale)
             .position_centered()
             .build()
-            .unwrap();
-        let  canvas = window.into_canvas().build().unwrap();
-        Self(canvas)
+            .map_err(|e| e.to_string())?;
+        let canvas = window.into_canvas().build().map_err(|e| e.to_string())?;
+        Ok(Self(canvas))
     }
 
-    pub fn draw(&mut self, pixels: Box<[u8]>) {
+    //RGB24の画素を描画する
+    pub fn draw(&mut self, rgb: &[u8]) {
         let texture_creator = self.0.texture_creator();
         let mut texture = texture_creator
             .create_texture_streaming(PixelFormatEnum::RGB24, LCD_WIDTH as u32, LCD_HEIGHT as u32)
             .unwrap();
-        texture.update(None, &pixels, 480).unwrap();
+        texture.update(None, rgb, LCD_WIDTH * 3).unwrap();
         self.0.clear();
         self.0.copy(&texture, None, None).unwrap();
         self.0.present();
-        println!("DRAW CALLED!");
     }
 }
\ No newline at end of file
@@ -3,6 +3,16 @@
 pub mod bootrom;
 pub mod gameboy;
 pub mod cartridge;
+pub mod joypad;
+pub mod serial;
+pub mod testrom;
+pub mod frontend;
+pub mod palette;
+pub mod gbs;
+pub mod rtc;
+pub mod header;
+pub mod save;
+pub mod mbc;
 mod interruputs;
 mod hram;
 mod wram;
@@ -12,5 +22,12 @@ mod instruction;
 mod operand;
 mod cpu;
 mod ppu;
+mod timer;
+mod dma;
+mod apu;
+mod blip;
+mod wav;
+mod vgm;
+mod scope;
 mod lcd;
-mod mbc;
\ No newline at end of file
+mod audio;
\ No newline at end of file
@@ -1,44 +1,281 @@
 use sdl2::pixels::Color;
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
-use core::panic;
-use std::fs::File;
-use std::io::{self, Read};
-use std::path::PathBuf;
+use std::env;
+use std::fs;
+use std::io;
+use std::process::ExitCode;
 
 use gbemu_rust::bootrom::Bootrom;
-use gbemu_rust::gameboy::GameBoy;
+use gbemu_rust::gameboy::{GameBoy, Model, LCD_WIDTH, LCD_HEIGHT};
 use gbemu_rust::cartridge::Cartridge;
+use gbemu_rust::frontend::{FrontendConfig, SdlFrontend};
+use gbemu_rust::gbs::Gbs;
+use gbemu_rust::palette::Palette;
+use gbemu_rust::save::SaveFile;
 
-fn main() {
-    //gameboy::run();
+const USAGE: &str = "\
+usage: gbemu-rust [OPTIONS] ROM|GBS
+
+options:
+  --bootrom PATH      run the boot ROM first (default: skip boot; not for GBS)
+  --scale N           window scale (default 4)
+  --palette NAME      gray, green or pocket (default gray)
+  --model NAME        dmg or mgb (default dmg; not for GBS)
+  --track N           GBS song to play first (default: the file's first song)
+  --mute              disable sound
+  --fast-forward      run as fast as possible
+  --headless FRAMES   run FRAMES frames without a window and exit
+  --screenshot PATH   save the last frame as a BMP file on exit
+  --scope-screenshot PATH
+                      save the channel waveforms as a BMP file on exit
+  --record-audio PATH record all sound to a WAV file
+  --vgm PATH          log sound register writes to a VGM file
+  -h, --help          show this help";
+
+struct Options {
+    rom: String,
+    bootrom: Option<String>,
+    model: Option<Model>,
+    track: Option<u8>,
+    frontend: FrontendConfig,
+    headless: Option<u32>,
+    screenshot: Option<String>,
+    scope_screenshot: Option<String>,
+    record_audio: Option<String>,
+    vgm: Option<String>,
+}
+
+fn main() -> ExitCode {
     unsafe {
         backtrace_on_stack_overflow::enable();
     }
-    let bootrom_path = PathBuf::from("asset/dmg_bootrom.bin");
-    println!("load to {:?}", bootrom_path);
-    let boot_vec = file2vec(&"asset/dmg_bootrom.bin".to_string());
-    let bootrom = Bootrom::new(boot_vec);
-    let cartridge_box = file_to_boxed_slice(&"asset/cpu_instrs.gb").unwrap();
-    let cartridge = Cartridge::new(cartridge_box);
-    let mut gb = GameBoy::new(bootrom, cartridge);
-    gb.run();
+    let opts = match parse_args(env::args().skip(1)) {
+        Ok(Some(opts)) => opts,
+        Ok(None) => {
+            println!("{}", USAGE);
+            return ExitCode::SUCCESS;
+        },
+        Err(e) => {
+            eprintln!("error: {}\n\n{}", e, USAGE);
+            return ExitCode::FAILURE;
+        },
+    };
+    match run(opts) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            ExitCode::FAILURE
+        },
+    }
 }
 
-fn file2vec(fname: &String) -> Vec<u8> {
-    if let Ok(mut file) = File::open(fname) {
-        let mut ret = vec![];
-        file.read_to_end(&mut ret).unwrap();
-        ret
+fn run(opts: Options) -> Result<(), String> {
+    let data = read_file(&opts.rom)?;
+    //GBSはヘッダの"GBS"で見分ける
+    let gbs = if data.starts_with(b"GBS") {
+        Some(Gbs::parse(&data).map_err(|e| format!("cannot load {}: {}", opts.rom, e))?)
     } else {
-        panic!("Cannot open {}", fname);
+        None
+    };
+    let mut save = None;
+    let (mut gb, song) = match &gbs {
+        Some(gbs) => {
+            //GBSはブートROMを通さず、DMGとして再生する
+            if opts.bootrom.is_some() || opts.model.is_some() {
+                return Err("--bootrom and --model cannot be used with a GBS file".to_string());
+            }
+            let song = match opts.track {
+                Some(n) if n == 0 || n > gbs.song_count => {
+                    return Err(format!("--track must be between 1 and {}", gbs.song_count));
+                },
+                Some(n) => n - 1,
+                None => gbs.default_song(),
+            };
+            (GameBoy::from_gbs(gbs, song), song)
+        },
+        None => {
+            let bootrom = match &opts.bootrom {
+                Some(path) => Bootrom::new(read_file(path)?),
+                None => Bootrom::disabled(),
+            };
+            let cartridge = Cartridge::new(data.into_boxed_slice())
+                .map_err(|e| format!("cannot load {}: {}", opts.rom, e))?;
+            let mut gb = GameBoy::with_model(bootrom, cartridge, opts.model.unwrap_or_default());
+            //電池付きのカートリッジは<rom>.savからSRAMを読み込む
+            if gb.has_battery() {
+                let file = SaveFile::for_rom(&opts.rom);
+                file.load(&mut gb)
+                    .map_err(|e| format!("cannot load {}: {}", file.path().display(), e))?;
+                save = Some(file);
+            }
+            (gb, 0)
+        },
+    };
+
+    //録音はサンプルレートを音声デバイスに合わせてから始める
+    let mut frontend = match opts.headless {
+        Some(_) => None,
+        None => Some(SdlFrontend::new(opts.frontend.clone())?),
+    };
+    if let Some(rate) = frontend.as_ref().and_then(|f| f.sample_rate()) {
+        gb.set_sample_rate(rate);
+    }
+    if let Some(path) = &opts.record_audio {
+        gb.start_audio_recording(path)
+            .map_err(|e| format!("cannot write {}: {}", path, e))?;
+    }
+    if let Some(path) = &opts.vgm {
+        gb.start_vgm_log(path)
+            .map_err(|e| format!("cannot write {}: {}", path, e))?;
+    }
+
+    match (&mut frontend, opts.headless) {
+        (Some(frontend), _) => match &gbs {
+            Some(gbs) => frontend.run_gbs(&mut gb, gbs, song),
+            None => {
+                if let Some(save) = save.take() {
+                    frontend.set_save_file(save);
+                }
+                frontend.run(&mut gb);
+            },
+        },
+        (None, frames) => {
+            for _ in 0..frames.unwrap_or(0) {
+                gb.run_frame();
+                //取り出さないと溜まり続けるので捨てる 録音には残っている
+                gb.drain_samples();
+                if let Some(save) = &mut save && let Err(e) = save.autosave(&mut gb) {
+                    eprintln!("warning: cannot write {}: {}", save.path().display(), e);
+                }
+            }
+            if let Some(save) = &mut save {
+                save.save(&mut gb)
+                    .map_err(|e| format!("cannot write {}: {}", save.path().display(), e))?;
+            }
+        },
     }
+
+    if let Some(path) = &opts.record_audio {
+        gb.stop_audio_recording()
+            .map_err(|e| format!("cannot write {}: {}", path, e))?;
+    }
+    if let Some(path) = &opts.vgm {
+        gb.stop_vgm_log()
+            .map_err(|e| format!("cannot write {}: {}", path, e))?;
+    }
+    if let Some(path) = &opts.screenshot {
+        let rgb = opts.frontend.palette.to_rgb(gb.framebuffer());
+        save_bmp(path, &rgb, LCD_WIDTH, LCD_HEIGHT)
+            .map_err(|e| format!("cannot write {}: {}", path, e))?;
+    }
+    if let Some(path) = &opts.scope_screenshot {
+        let rgb = opts.frontend.palette.to_rgb(&gb.render_oscilloscope());
+        save_bmp(path, &rgb, LCD_WIDTH, LCD_HEIGHT)
+            .map_err(|e| format!("cannot write {}: {}", path, e))?;
+    }
+    Ok(())
 }
 
-fn file_to_boxed_slice(path: &str) -> io::Result<Box<[u8]>> {
-    let vec_data = file2vec(&path.to_string());
-    let boxed_slice:Box<[u8]> = vec_data.into_boxed_slice();
-    Ok(boxed_slice)
+//--helpならNoneを返す
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Option<Options>, String> {
+    let mut rom = None;
+    let mut bootrom = None;
+    let mut model = None;
+    let mut track = None;
+    let mut frontend = FrontendConfig::default();
+    let mut headless = None;
+    let mut screenshot = None;
+    let mut scope_screenshot = None;
+    let mut record_audio = None;
+    let mut vgm = None;
+
+    while let Some(arg) = args.next() {
+        let mut value = |name: &str| args.next().ok_or(format!("{} needs a value", name));
+        match arg.as_str() {
+            "--bootrom" => bootrom = Some(value("--bootrom")?),
+            "--scale" => frontend.scale = match value("--scale")?.parse() {
+                Ok(n) if n > 0 => n,
+                _ => return Err("--scale needs a positive number".to_string()),
+            },
+            "--palette" => {
+                let name = value("--palette")?;
+                frontend.palette = Palette::from_name(&name).ok_or(format!(
+                    "unknown palette {} (expected one of {})", name, Palette::NAMES.join(", ")
+                ))?;
+            },
+            "--model" => {
+                let name = value("--model")?;
+                model = Some(Model::from_name(&name).ok_or(format!(
+                    "unknown model {} (expected one of {})", name, Model::NAMES.join(", ")
+                ))?);
+            },
+            "--track" => track = match value("--track")?.parse() {
+                Ok(n) => Some(n),
+                Err(_) => return Err("--track needs a song number".to_string()),
+            },
+            "--mute" => frontend.mute = true,
+            "--fast-forward" => frontend.fast_forward = true,
+            "--headless" => headless = match value("--headless")?.parse() {
+                Ok(n) => Some(n),
+                Err(_) => return Err("--headless needs a frame count".to_string()),
+            },
+            "--screenshot" => screenshot = Some(value("--screenshot")?),
+            "--scope-screenshot" => scope_screenshot = Some(value("--scope-screenshot")?),
+            "--record-audio" => record_audio = Some(value("--record-audio")?),
+            "--vgm" => vgm = Some(value("--vgm")?),
+            "-h" | "--help" => return Ok(None),
+            _ if arg.starts_with('-') => return Err(format!("unknown option {}", arg)),
+            _ if rom.is_some() => return Err(format!("unexpected argument {}", arg)),
+            _ => rom = Some(arg),
+        }
+    }
+
+    Ok(Some(Options {
+        rom: rom.ok_or("no ROM given")?,
+        bootrom,
+        model,
+        track,
+        frontend,
+        headless,
+        screenshot,
+        scope_screenshot,
+        record_audio,
+        vgm,
+    }))
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|e| format!("cannot open {}: {}", path, e))
+}
+
+//24bitの非圧縮BMPで保存する
+fn save_bmp(path: &str, rgb: &[u8], width: usize, height: usize) -> io::Result<()> {
+    let stride = (width * 3 + 3) & !3;
+    let image_size = stride * height;
+    let mut data = Vec::with_capacity(54 + image_size);
+    //BITMAPFILEHEADER
+    data.extend_from_slice(b"BM");
+    data.extend_from_slice(&(54 + image_size as u32).to_le_bytes());
+    data.extend_from_slice(&[0; 4]);
+    data.extend_from_slice(&54u32.to_le_bytes());
+    //BITMAPINFOHEADER
+    data.extend_from_slice(&40u32.to_le_bytes());
+    data.extend_from_slice(&(width as i32).to_le_bytes());
+    data.extend_from_slice(&(height as i32).to_le_bytes());
+    data.extend_from_slice(&1u16.to_le_bytes());
+    data.extend_from_slice(&24u16.to_le_bytes());
+    data.extend_from_slice(&[0; 4]);
+    data.extend_from_slice(&(image_size as u32).to_le_bytes());
+    data.extend_from_slice(&[0; 16]);
+    //下の行から、BGRの順
+    for row in rgb.chunks(width * 3).rev() {
+        for px in row.chunks(3) {
+            data.extend_from_slice(&[px[2], px[1], px[0]]);
+        }
+        data.resize(data.len() + stride - width * 3, 0);
+    }
+    fs::write(path, data)
 }
 
 fn test_sdl2() {
@@ -1,78 +1,519 @@
+use std::sync::Mutex;
 
+use crate::header::NINTENDO_LOGO;
+use crate::rtc::{Rtc, SystemClock, RTC_S, RTC_DH};
 
-pub enum Mbc {
-    NoMbc,
-    Mbc1 {
-        sram_enable: bool,
-        low_bank: usize,
-        high_bank: usize,
-        bank_mode: bool,
-        rom_banks: usize,
-    },
-}
-impl Mbc {
-    pub fn new(cartridge_type: u8, rom_banks: usize) -> Self {
-        match cartridge_type {
-            0x00 | 0x08 | 0x09 => Self::NoMbc,
-            0x01..=0x03 => Self::Mbc1 {
-                sram_enable: false,
-                low_bank: 0b00001,
-                high_bank: 0b00,
-                bank_mode: false,
-                rom_banks,
+//カートリッジのROMとRAMへのアクセスをバンク切り替えのレジスタに合わせて振り分ける
+pub trait Mapper {
+    //0x0000~0x7FFF
+    fn read_rom(&self, addr: u16) -> u8;
+    //0x0000~0x7FFFへの書き込みはMBCのレジスタに届く
+    fn write_register(&mut self, addr: u16, val: u8);
+    //0xA000~0xBFFF
+    fn read_ram(&self, addr: u16) -> u8;
+    //RAM(やRTC)に書き込めたらtrue 無効なときや無いときは捨ててfalse
+    fn write_ram(&mut self, addr: u16, val: u8) -> bool;
+
+    fn ram(&self) -> &Ram;
+    fn ram_mut(&mut self) -> &mut Ram;
+
+    //.savファイルに書く内容 RTCなどを持つMBCは末尾に続ける
+    fn save_data(&mut self) -> Vec<u8> {
+        self.ram().data.to_vec()
+    }
+
+    //大きさが違うファイルも読める分だけ読む
+    fn load_save_data(&mut self, data: &[u8]) {
+        let ram = &mut self.ram_mut().data;
+        let len = ram.len().min(data.len());
+        ram[..len].copy_from_slice(&data[..len]);
+    }
+
+    fn rtc_mut(&mut self) -> Option<&mut Rtc> {
+        None
+    }
+
+    //振動カートリッジのモーターが回っているか
+    fn rumble(&self) -> bool {
+        false
+    }
+
+    //加速度センサー付きのカートリッジに傾き(-1.0~1.0)を伝える
+    fn set_tilt(&mut self, _x: f32, _y: f32) {}
+}
+
+//ROMを0x4000バイトのバンク単位で読む 大きさは2の累乗なので、超えたバンクは下のバンクの繰り返しになる
+pub struct Rom(Box<[u8]>);
+impl Rom {
+    fn read(&self, bank: usize, addr: u16) -> u8 {
+        self.0[((bank << 14) | (addr & 0x3FFF) as usize) & (self.0.len() - 1)]
+    }
+}
+
+//カートリッジのRAMを0x2000バイトのバンク単位で読み書きする 無効なときや無いときは0xFFが読める
+pub struct Ram {
+    data: Box<[u8]>,
+    enabled: bool,
+}
+impl Ram {
+    pub fn new(size: usize) -> Self {
+        Self {
+            data: vec![0; size].into(),
+            enabled: false,
+        }
+    }
+
+    //MBCの無いカートリッジでは常に読み書きできる
+    pub fn always_enabled(size: usize) -> Self {
+        Self {
+            enabled: true,
+            ..Self::new(size)
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    //0x0000~0x1FFFへの書き込み 下位4bitが0xAなら有効になる
+    pub fn write_enable(&mut self, val: u8) {
+        self.enabled = val & 0xF == 0xA;
+    }
+
+    fn index(&self, bank: usize, addr: u16) -> usize {
+        ((bank << 13) | (addr & 0x1FFF) as usize) & (self.data.len() - 1)
+    }
+
+    pub fn read(&self, bank: usize, addr: u16) -> u8 {
+        if !self.enabled || self.is_empty() {
+            return 0xFF;
+        }
+        self.data[self.index(bank, addr)]
+    }
+
+    pub fn write(&mut self, bank: usize, addr: u16, val: u8) -> bool {
+        if !self.enabled || self.is_empty() {
+            return false;
+        }
+        let i = self.index(bank, addr);
+        self.data[i] = val;
+        true
+    }
+}
+
+//ヘッダのカートリッジの種類ごとの名前と、電池の有無と、マッパーの作り方
+pub struct MapperInfo {
+    pub name: &'static str,
+    pub battery: bool,
+    //ROM(2の累乗に揃えたもの)とヘッダのSRAMの大きさから作る
+    pub new: fn(Box<[u8]>, usize) -> Box<dyn Mapper>,
+}
+
+//新しいMBCはここに足す 別のモジュールのマッパーはregisterを使う
+const REGISTRY: &[(u8, MapperInfo)] = &[
+    (0x00, MapperInfo { name: "ROM ONLY", battery: false, new: |rom, ram| Box::new(NoMbc::new(rom, ram)) }),
+    (0x01, MapperInfo { name: "MBC1", battery: false, new: |rom, ram| Box::new(Mbc1::new(rom, ram)) }),
+    (0x02, MapperInfo { name: "MBC1+RAM", battery: false, new: |rom, ram| Box::new(Mbc1::new(rom, ram)) }),
+    (0x03, MapperInfo { name: "MBC1+RAM+BATTERY", battery: true, new: |rom, ram| Box::new(Mbc1::new(rom, ram)) }),
+    (0x05, MapperInfo { name: "MBC2", battery: false, new: |rom, _| Box::new(Mbc2::new(rom)) }),
+    (0x06, MapperInfo { name: "MBC2+BATTERY", battery: true, new: |rom, _| Box::new(Mbc2::new(rom)) }),
+    (0x08, MapperInfo { name: "ROM+RAM", battery: false, new: |rom, ram| Box::new(NoMbc::new(rom, ram)) }),
+    (0x09, MapperInfo { name: "ROM+RAM+BATTERY", battery: true, new: |rom, ram| Box::new(NoMbc::new(rom, ram)) }),
+    (0x0F, MapperInfo { name: "MBC3+TIMER+BATTERY", battery: true, new: |rom, ram| Box::new(Mbc3::new(rom, ram, true)) }),
+    (0x10, MapperInfo { name: "MBC3+TIMER+RAM+BATTERY", battery: true, new: |rom, ram| Box::new(Mbc3::new(rom, ram, true)) }),
+    (0x11, MapperInfo { name: "MBC3", battery: false, new: |rom, ram| Box::new(Mbc3::new(rom, ram, false)) }),
+    (0x12, MapperInfo { name: "MBC3+RAM", battery: false, new: |rom, ram| Box::new(Mbc3::new(rom, ram, false)) }),
+    (0x13, MapperInfo { name: "MBC3+RAM+BATTERY", battery: true, new: |rom, ram| Box::new(Mbc3::new(rom, ram, false)) }),
+    (0x19, MapperInfo { name: "MBC5", battery: false, new: |rom, ram| Box::new(Mbc5::new(rom, ram, false)) }),
+    (0x1A, MapperInfo { name: "MBC5+RAM", battery: false, new: |rom, ram| Box::new(Mbc5::new(rom, ram, false)) }),
+    (0x1B, MapperInfo { name: "MBC5+RAM+BATTERY", battery: true, new: |rom, ram| Box::new(Mbc5::new(rom, ram, false)) }),
+    (0x1C, MapperInfo { name: "MBC5+RUMBLE", battery: false, new: |rom, ram| Box::new(Mbc5::new(rom, ram, true)) }),
+    (0x1D, MapperInfo { name: "MBC5+RUMBLE+RAM", battery: false, new: |rom, ram| Box::new(Mbc5::new(rom, ram, true)) }),
+    (0x1E, MapperInfo { name: "MBC5+RUMBLE+RAM+BATTERY", battery: true, new: |rom, ram| Box::new(Mbc5::new(rom, ram, true)) }),
+];
+
+//mbc.rsの外で書いたマッパーはregisterで足す 同じ種類ならREGISTRYより優先する
+static REGISTERED: Mutex<Vec<(u8, &'static MapperInfo)>> = Mutex::new(Vec::new());
+
+pub fn register(cartridge_type: u8, info: MapperInfo) {
+    let info: &'static MapperInfo = Box::leak(Box::new(info));
+    REGISTERED.lock().unwrap().push((cartridge_type, info));
+}
+
+//対応していない種類ならNone
+pub fn lookup(cartridge_type: u8) -> Option<&'static MapperInfo> {
+    let registered = REGISTERED.lock().unwrap().iter().rev()
+        .find(|&&(t, _)| t == cartridge_type)
+        .map(|&(_, info)| info);
+    registered.or_else(|| REGISTRY.iter().find(|&&(t, _)| t == cartridge_type).map(|(_, info)| info))
+}
+
+//バンク切り替えの無い32KiBのROM
+pub struct NoMbc {
+    rom: Rom,
+    ram: Ram,
+}
+impl NoMbc {
+    pub fn new(rom: Box<[u8]>, ram_size: usize) -> Self {
+        Self {
+            rom: Rom(rom),
+            ram: Ram::always_enabled(ram_size),
+        }
+    }
+}
+impl Mapper for NoMbc {
+    fn read_rom(&self, addr: u16) -> u8 {
+        self.rom.read((addr >> 14) as usize, addr)
+    }
+
+    fn write_register(&mut self, _addr: u16, _val: u8) {}
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        self.ram.read(0, addr)
+    }
+
+    fn write_ram(&mut self, addr: u16, val: u8) -> bool {
+        self.ram.write(0, addr, val)
+    }
+
+    fn ram(&self) -> &Ram {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut Ram {
+        &mut self.ram
+    }
+}
+
+pub struct Mbc1 {
+    rom: Rom,
+    ram: Ram,
+    low_bank: usize, //5bit 0は1として扱う
+    high_bank: usize, //2bit
+    bank_mode: bool, //trueなら0x0000~0x3FFFとRAMもhigh_bankで切り替える
+    multicart: bool, //MBC1M low_bankの上位1bitが配線されておらず、high_bankは4bit目から
+}
+impl Mbc1 {
+    pub fn new(rom: Box<[u8]>, ram_size: usize) -> Self {
+        Self {
+            multicart: is_multicart(&rom),
+            rom: Rom(rom),
+            ram: Ram::new(ram_size),
+            low_bank: 0b00001,
+            high_bank: 0b00,
+            bank_mode: false,
+        }
+    }
+
+    //ROMの大きさを超えるbitはRomでマスクされる
+    fn high_rom_bank(&self) -> usize {
+        self.high_bank << if self.multicart { 4 } else { 5 }
+    }
+
+    fn ram_bank(&self) -> usize {
+        if self.bank_mode { self.high_bank } else { 0 }
+    }
+}
+impl Mapper for Mbc1 {
+    fn read_rom(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF if self.bank_mode => self.rom.read(self.high_rom_bank(), addr),
+            0x0000..=0x3FFF => self.rom.read(0, addr),
+            _ => {
+                let low_bits = if self.multicart { 0b1111 } else { 0b11111 };
+                self.rom.read(self.high_rom_bank() | (self.low_bank & low_bits), addr)
             },
-            _ => panic!("Not supported: {:02x}", cartridge_type),
-        }
-    }
-
-    pub fn write(&mut self, addr: u16, val: u8) {
-        match *self {
-            Self::NoMbc => {},
-            Self::Mbc1 {
-                ref mut sram_enable,
-                ref mut low_bank,
-                ref mut high_bank,
-                ref mut bank_mode,
-                ..
-            } => match addr {
-                0x0000..=0x1FFF => *sram_enable = val & 0xF == 0xA,
-                0x2000..=0x3FFF => *low_bank = if val & 0b1111 == 0b00000 {
-                    0b0001
-                } else {
-                    (val & 0b11111) as usize
-                },
-                0x4000..=0x5FFF => *high_bank = (val & 0b11) as usize,
-                0x6000..=0x7FFF => *bank_mode = val & 0b1 > 0,
-                _ => unreachable!(),
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram.write_enable(val),
+            //0かどうかは配線されていないbitも含めた5bitで判定する
+            0x2000..=0x3FFF => self.low_bank = ((val & 0b11111) as usize).max(1),
+            0x4000..=0x5FFF => self.high_bank = (val & 0b11) as usize,
+            0x6000..=0x7FFF => self.bank_mode = val & 0b1 > 0,
+            _ => unreachable!(),
+        }
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        self.ram.read(self.ram_bank(), addr)
+    }
+
+    fn write_ram(&mut self, addr: u16, val: u8) -> bool {
+        self.ram.write(self.ram_bank(), addr, val)
+    }
+
+    fn ram(&self) -> &Ram {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut Ram {
+        &mut self.ram
+    }
+}
+
+//MBC1M(複数のゲームを収めた8MbitのROM)は、各ゲームの先頭になるバンク0x10にもロゴがある
+fn is_multicart(rom: &[u8]) -> bool {
+    const BANK_0X10_LOGO: usize = 0x10 * 0x4000 + 0x104;
+    rom.len() == 0x100000 && rom[BANK_0X10_LOGO..BANK_0X10_LOGO + NINTENDO_LOGO.len()] == NINTENDO_LOGO
+}
+
+//512 x 4bitのRAMを内蔵している
+pub struct Mbc2 {
+    rom: Rom,
+    ram: Ram,
+    rom_bank: usize, //4bit 0は1として扱う
+}
+impl Mbc2 {
+    pub fn new(rom: Box<[u8]>) -> Self {
+        Self {
+            rom: Rom(rom),
+            ram: Ram::new(0x200),
+            rom_bank: 1,
+        }
+    }
+}
+impl Mapper for Mbc2 {
+    fn read_rom(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => self.rom.read(0, addr),
+            _ => self.rom.read(self.rom_bank, addr),
+        }
+    }
+
+    //アドレスのbit8でレジスタを選ぶ
+    fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000..=0x3FFF if addr & 0x100 == 0 => self.ram.write_enable(val),
+            0x0000..=0x3FFF => self.rom_bank = ((val & 0x0F) as usize).max(1),
+            _ => {},
+        }
+    }
+
+    //0xA000~0xBFFFに512バイトが繰り返し見える 下位4bitだけが記憶され、上位4bitは1が読める
+    fn read_ram(&self, addr: u16) -> u8 {
+        0xF0 | self.ram.read(0, addr)
+    }
+
+    fn write_ram(&mut self, addr: u16, val: u8) -> bool {
+        self.ram.write(0, addr, val & 0x0F)
+    }
+
+    fn ram(&self) -> &Ram {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut Ram {
+        &mut self.ram
+    }
+}
+
+pub struct Mbc3 {
+    rom: Rom,
+    ram: Ram, //有効にするとRTCのレジスタも読み書きできる
+    rom_bank: usize, //7bit 0は1として扱う
+    ram_bank: u8, //0x00~0x03はRAMのバンク、0x08~0x0CはRTCのレジスタ
+    rtc: Option<Rtc>,
+}
+impl Mbc3 {
+    pub fn new(rom: Box<[u8]>, ram_size: usize, rtc: bool) -> Self {
+        Self {
+            rom: Rom(rom),
+            ram: Ram::new(ram_size),
+            rom_bank: 1,
+            ram_bank: 0,
+            rtc: rtc.then(|| Rtc::new(Box::new(SystemClock))),
+        }
+    }
+
+    //RTCのレジスタが選ばれていればその番号
+    fn rtc_register(&self) -> Option<u8> {
+        (self.rtc.is_some() && (RTC_S..=RTC_DH).contains(&self.ram_bank)).then_some(self.ram_bank)
+    }
+}
+impl Mapper for Mbc3 {
+    fn read_rom(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => self.rom.read(0, addr),
+            _ => self.rom.read(self.rom_bank, addr),
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram.write_enable(val),
+            0x2000..=0x3FFF => self.rom_bank = ((val & 0x7F) as usize).max(1),
+            0x4000..=0x5FFF => self.ram_bank = val & 0x0F,
+            0x6000..=0x7FFF => if let Some(rtc) = &mut self.rtc {
+                rtc.write_latch(val);
             },
+            _ => unreachable!(),
         }
     }
 
-    pub fn get_addr(&self, addr: u16) -> usize {
-        match *self {
-            Self::NoMbc => addr as usize,
-            Self::Mbc1 {
-                low_bank,
-                high_bank,
-                bank_mode,
-                rom_banks,
-                ..
-            } => match addr {
-                0x0000..=0x3FFF => if bank_mode {
-                    (high_bank << 19) | (addr & 0x3FFF) as usize
-                } else {
-                    (addr & 0x3FFF) as usize
-                },
-                0x4000..=0x7FFF => (high_bank << 19) | ((rom_banks & (rom_banks - 1)) << 14) | (addr & 0x3FFF) as usize,
-                0xA000..=0xBFFF => if bank_mode {
-                    (high_bank << 13) | (addr & 0x1FFF) as usize
-                } else {
-                    (addr & 0x1FFF) as usize
+    fn read_ram(&self, addr: u16) -> u8 {
+        match (&self.rtc, self.rtc_register()) {
+            _ if !self.ram.enabled => 0xFF,
+            (Some(rtc), Some(reg)) => rtc.read(reg),
+            _ => self.ram.read((self.ram_bank & 0x03) as usize, addr),
+        }
+    }
+
+    fn write_ram(&mut self, addr: u16, val: u8) -> bool {
+        match self.rtc_register() {
+            _ if !self.ram.enabled => false,
+            Some(reg) => {
+                self.rtc.as_mut().unwrap().write(reg, val);
+                true
+            },
+            None => self.ram.write((self.ram_bank & 0x03) as usize, addr, val),
+        }
+    }
+
+    fn ram(&self) -> &Ram {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut Ram {
+        &mut self.ram
+    }
+
+    //RAMの後にRTCの状態を続ける
+    fn save_data(&mut self) -> Vec<u8> {
+        let mut data = self.ram.data.to_vec();
+        if let Some(rtc) = &mut self.rtc {
+            data.extend_from_slice(&rtc.save());
+        }
+        data
+    }
+
+    fn load_save_data(&mut self, data: &[u8]) {
+        let len = self.ram.data.len().min(data.len());
+        self.ram.data[..len].copy_from_slice(&data[..len]);
+        if let Some(rtc) = &mut self.rtc && data.len() > len {
+            rtc.load(&data[len..]);
+        }
+    }
+
+    fn rtc_mut(&mut self) -> Option<&mut Rtc> {
+        self.rtc.as_mut()
+    }
+}
+
+pub struct Mbc5 {
+    rom: Rom,
+    ram: Ram,
+    rom_bank: usize, //9bit 0も選べる
+    ram_bank: usize, //4bit 振動カートリッジでは3bit
+    rumble: Option<bool>, //振動カートリッジならモーターが回っているか
+}
+impl Mbc5 {
+    pub fn new(rom: Box<[u8]>, ram_size: usize, rumble: bool) -> Self {
+        Self {
+            rom: Rom(rom),
+            ram: Ram::new(ram_size),
+            rom_bank: 1,
+            ram_bank: 0,
+            rumble: rumble.then_some(false),
+        }
+    }
+}
+impl Mapper for Mbc5 {
+    fn read_rom(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => self.rom.read(0, addr),
+            _ => self.rom.read(self.rom_bank, addr),
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram.write_enable(val),
+            0x2000..=0x2FFF => self.rom_bank = (self.rom_bank & 0x100) | val as usize,
+            0x3000..=0x3FFF => self.rom_bank = (self.rom_bank & 0xFF) | ((val & 0x01) as usize) << 8,
+            //振動カートリッジではbit3がモーター
+            0x4000..=0x5FFF => match &mut self.rumble {
+                Some(motor) => {
+                    *motor = val & 0x08 > 0;
+                    self.ram_bank = (val & 0x07) as usize;
                 },
-                _ => unreachable!(),
-            }
+                None => self.ram_bank = (val & 0x0F) as usize,
+            },
+            0x6000..=0x7FFF => {},
+            _ => unreachable!(),
         }
     }
 
+    fn read_ram(&self, addr: u16) -> u8 {
+        self.ram.read(self.ram_bank, addr)
+    }
+
+    fn write_ram(&mut self, addr: u16, val: u8) -> bool {
+        self.ram.write(self.ram_bank, addr, val)
+    }
+
+    fn ram(&self) -> &Ram {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut Ram {
+        &mut self.ram
+    }
 
-}
\ No newline at end of file
+    fn rumble(&self) -> bool {
+        self.rumble == Some(true)
+    }
+}
+
+//GBSの再生用 0x2000~0x3FFFへの書き込みで0x4000~0x7FFFのバンクを切り替える
+//A000~BFFFは常に使えるRAMになる
+pub struct GbsMapper {
+    rom: Rom,
+    ram: Ram,
+    rom_bank: usize,
+}
+impl GbsMapper {
+    pub fn new(rom: Box<[u8]>) -> Self {
+        Self {
+            rom: Rom(rom),
+            ram: Ram::always_enabled(0x2000),
+            rom_bank: 1,
+        }
+    }
+}
+impl Mapper for GbsMapper {
+    fn read_rom(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x3FFF => self.rom.read(0, addr),
+            _ => self.rom.read(self.rom_bank, addr),
+        }
+    }
+
+    fn write_register(&mut self, addr: u16, val: u8) {
+        if let 0x2000..=0x3FFF = addr {
+            self.rom_bank = (val as usize).max(1);
+        }
+    }
+
+    fn read_ram(&self, addr: u16) -> u8 {
+        self.ram.read(0, addr)
+    }
+
+    fn write_ram(&mut self, addr: u16, val: u8) -> bool {
+        self.ram.write(0, addr, val)
+    }
+
+    fn ram(&self) -> &Ram {
+        &self.ram
+    }
+
+    fn ram_mut(&mut self) -> &mut Ram {
+        &mut self.ram
+    }
+}
@@ -1,16 +1,23 @@
 use crate::peripherals::*;
 use crate::cpu::*;
 
+//命令の中から呼ばれるオペランドの読み書きは、呼び出し元と別の状態(self.operand_stateなど)を指定して使う
 macro_rules! step {
+    ($cpu:ident . $state:ident, $d:expr, {$($c:tt : $e:expr,)*}) => {
+        $(if $cpu.$state.step == $c { $e })* else { return $d; }
+    };
     ($cpu:ident, $d:expr, {$($c:tt : $e:expr,)*}) => {
-        $(if $cpu.exec_state.step == $c { $e })* else { return $d; }
+        step!($cpu.exec_state, $d, {$($c : $e,)*})
     };
 } 
 pub(crate) use step;
 macro_rules! go {
+    ($cpu:ident . $state:ident, $d:expr) => {
+        $cpu.$state.step = $d;
+    };
     ($cpu:ident, $d:expr) => {
-        $cpu.exec_state.step = $d;
-    }
+        go!($cpu.exec_state, $d)
+    };
 }
 pub(crate) use go;
 
@@ -89,16 +96,16 @@ impl IO16<Reg16> for Cpu {
 
 impl IO8<Imm8> for Cpu {
     fn read8(&mut self, bus:&Peripherals, _: Imm8) -> Option<u8> {
-        step!(self, None, {
+        step!(self.imm_state, None, {
             0: {
-                self.exec_state.val8 = bus.read(&mut self.interrupts, self.regs.pc);
+                self.imm_state.val8 = bus.read(&mut self.interrupts, self.regs.pc);
                 self.regs.pc = self.regs.pc.wrapping_add(1);
-                go!(self, 1);
+                go!(self.imm_state, 1);
                 return None;
             },
             1: {
-                go!(self, 0);
-                return Some(self.exec_state.val8);
+                go!(self.imm_state, 0);
+                return Some(self.imm_state.val8);
             },
         });
     }
@@ -109,18 +116,18 @@ impl IO8<Imm8> for Cpu {
 
 impl IO16<Imm16> for Cpu {
     fn read16(&mut self, bus: &Peripherals, _: Imm16) -> Option<u16> {
-        step!(self, None, {
+        step!(self.operand_state, None, {
             0: if let Some(lo) = self.read8(bus, Imm8) {
-                self.exec_state.val8 = lo;
-                go!(self, 1);
+                self.operand_state.val8 = lo;
+                go!(self.operand_state, 1);
             },
             1: if let Some(hi) = self.read8(bus, Imm8) {
-                self.exec_state.val16 = u16::from_le_bytes([self.exec_state.val8, hi]);
-                go!(self, 2);
+                self.operand_state.val16 = u16::from_le_bytes([self.operand_state.val8, hi]);
+                go!(self.operand_state, 2);
             },
             2: {
-                go!(self, 0);
-                return Some(self.exec_state.val16);
+                go!(self.operand_state, 0);
+                return Some(self.operand_state.val16);
             },
         });
     }
@@ -132,9 +139,9 @@ impl IO16<Imm16> for Cpu {
 
 impl IO8<Indirect> for Cpu {
     fn read8(&mut self, bus:&Peripherals, src: Indirect) -> Option<u8> {
-        step!(self, None, {
+        step!(self.operand_state, None, {
             0: {
-                self.exec_state.val8 = match src {
+                self.operand_state.val8 = match src {
                     Indirect::BC => bus.read(&mut self.interrupts, self.regs.bc()),
                     Indirect::DE => bus.read(&mut self.interrupts, self.regs.de()),
                     Indirect::HL => bus.read(&mut self.interrupts, self.regs.hl()),
@@ -151,18 +158,18 @@ impl IO8<Indirect> for Cpu {
                         bus.read(&mut self.interrupts, addr)
                     },
                 };
-                go!(self, 1);
+                go!(self.operand_state, 1);
                 return None;
             },
             1: {
-                go!(self, 0);
-                return Some(self.exec_state.val8);
+                go!(self.operand_state, 0);
+                return Some(self.operand_state.val8);
             },
         });
     }
     
     fn write8(&mut self, bus: &mut Peripherals, dst: Indirect, val: u8) -> Option<()> {
-        step!(self, None, {
+        step!(self.operand_state, None, {
             0: {
                 match dst {
                 Indirect::BC => bus.write(&mut self.interrupts, self.regs.bc(), val),
@@ -180,11 +187,11 @@ impl IO8<Indirect> for Cpu {
                     bus.write(&mut self.interrupts, addr, val);
                 },
             }
-            go!(self, 1);
+            go!(self.operand_state, 1);
             return None;
         },
             1: {
-                go!(self, 0);
+                go!(self.operand_state, 0);
                 return Some(());
             },
         });
@@ -193,57 +200,57 @@ impl IO8<Indirect> for Cpu {
 
 impl IO8<Direct8> for Cpu {
     fn read8(&mut self, bus:&Peripherals, src: Direct8) -> Option<u8> {
-        step!(self, None, {
+        step!(self.operand_state, None, {
             0: if let Some(lo) = self.read8(bus, Imm8) {
-                self.exec_state.val8 = lo;
-                go!(self, 1);
+                self.operand_state.val8 = lo;
+                go!(self.operand_state, 1);
                 if let Direct8::DFF = src {
-                    self.exec_state.val16 = 0xFF00 | (lo as u16);
-                    go!(self, 2);
+                    self.operand_state.val16 = 0xFF00 | (lo as u16);
+                    go!(self.operand_state, 2);
                 }
             },
             1: if let Some(hi) = self.read8(bus, Imm8) {
-                self.exec_state.val16 = u16::from_le_bytes([self.exec_state.val8, hi]);
+                self.operand_state.val16 = u16::from_le_bytes([self.operand_state.val8, hi]);
                 // VAL16.store(u16::from_le_bytes([VAL8.load(Relaxed), hi]), Relaxed);
-                go!(self, 2);
+                go!(self.operand_state, 2);
             },
             2: {
-                self.exec_state.val8 = bus.read(&mut self.interrupts, self.exec_state.val16);
+                self.operand_state.val8 = bus.read(&mut self.interrupts, self.operand_state.val16);
                 // VAL8.store(bus.read(&mut self.interrupts, VAL16.load(Relaxed)), Relaxed);
-                go!(self, 3);
+                go!(self.operand_state, 3);
                 return None;
             },
             3: {
-                go!(self, 0);
-                return Some(self.exec_state.val8);
+                go!(self.operand_state, 0);
+                return Some(self.operand_state.val8);
             },
         });
     }
 
     fn write8(&mut self, bus: &mut Peripherals, dst: Direct8, val: u8) -> Option<()> {
-        step!(self, None, {
+        step!(self.operand_state, None, {
             0: if let Some(lo) = self.read8(bus, Imm8) {
-                self.exec_state.val8 = lo;
+                self.operand_state.val8 = lo;
                 // VAL8.store(lo, Relaxed);
-                go!(self, 1);
+                go!(self.operand_state, 1);
                 if let Direct8::DFF = dst {
-                    self.exec_state.val16 = 0xff | (lo as u16);
+                    self.operand_state.val16 = 0xFF00 | (lo as u16);
                     // VAL16.store(0xFF00 | (lo as u16), Relaxed);
-                    go!(self, 2);
+                    go!(self.operand_state, 2);
                 }
             },
             1: if let Some(hi) = self.read8(bus, Imm8) {
-                self.exec_state.val16 = u16::from_le_bytes([self.exec_state.val8, hi]);
+                self.operand_state.val16 = u16::from_le_bytes([self.operand_state.val8, hi]);
                 // VAL16.store(u16::from_le_bytes([VAL8.load(Relaxed), hi]), Relaxed);
-                go!(self, 2); 
+                go!(self.operand_state, 2); 
             },
             2: {
-                bus.write(&mut self.interrupts, self.exec_state.val16, val);
-                go!(self, 3);
+                bus.write(&mut self.interrupts, self.operand_state.val16, val);
+                go!(self.operand_state, 3);
                 return None;
             },
             3:{
-                go!(self, 0);
+                go!(self.operand_state, 0);
                 return Some(());
             },
         });
@@ -256,28 +263,28 @@ impl IO16<Direct16> for Cpu {
     }
 
     fn write16(&mut self, bus: &mut Peripherals, dst: Direct16, val: u16) -> Option<()> {
-        step!(self, None, {
+        step!(self.operand_state, None, {
             0: if let Some(lo) = self.read8(bus, Imm8) {
-                self.exec_state.val8 = lo;
+                self.operand_state.val8 = lo;
                 // VAL8.store(lo, Relaxed);
-                go!(self, 1);
+                go!(self.operand_state, 1);
             },
             1: if let Some(hi) = self.read8(bus, Imm8) {
-                self.exec_state.val16 = u16::from_le_bytes([self.exec_state.val8, hi]);
+                self.operand_state.val16 = u16::from_le_bytes([self.operand_state.val8, hi]);
                 // VAL16.store(u16::from_le_bytes([VAL8.load(Relaxed), hi]), Relaxed);
-                go!(self, 2);
+                go!(self.operand_state, 2);
             },
             2: {
-                bus.write(&mut self.interrupts, self.exec_state.val16, val as u8);
-                go!(self, 3);
+                bus.write(&mut self.interrupts, self.operand_state.val16, val as u8);
+                go!(self.operand_state, 3);
                 return None;
             },
             3: {
-                bus.write(&mut self.interrupts, self.exec_state.val16.wrapping_add(1), (val >> 8) as u8);
-                go!(self, 4);
+                bus.write(&mut self.interrupts, self.operand_state.val16.wrapping_add(1), (val >> 8) as u8);
+                go!(self.operand_state, 4);
                 return None;
             },
-            4: return Some(go!(self, 0)),
+            4: return Some(go!(self.operand_state, 0)),
         });
     }
 }
@@ -0,0 +1,47 @@
+//PPUが出力する明るさ(0xFF, 0xAA, 0x55, 0x00)を表示する色に変換する
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Palette {
+    #[default]
+    Gray,
+    Green, //初代DMGの液晶風
+    Pocket, //ゲームボーイポケット風
+}
+impl Palette {
+    pub const NAMES: [&'static str; 3] = ["gray", "green", "pocket"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "gray" => Some(Self::Gray),
+            "green" => Some(Self::Green),
+            "pocket" => Some(Self::Pocket),
+            _ => None,
+        }
+    }
+
+    //明るい順に4色
+    fn colors(&self) -> [[u8; 3]; 4] {
+        match self {
+            Self::Gray => [[0xFF; 3], [0xAA; 3], [0x55; 3], [0x00; 3]],
+            Self::Green => [[0x9B, 0xBC, 0x0F], [0x8B, 0xAC, 0x0F], [0x30, 0x62, 0x30], [0x0F, 0x38, 0x0F]],
+            Self::Pocket => [[0xC4, 0xCF, 0xA1], [0x8B, 0x95, 0x6D], [0x4D, 0x53, 0x3C], [0x1F, 0x1F, 0x1F]],
+        }
+    }
+
+    //画素ごとの明るさをRGB24に変換する
+    pub fn to_rgb(&self, pixels: &[u8]) -> Vec<u8> {
+        let colors = self.colors();
+        pixels.iter().flat_map(|&e| colors[(3 - e / 0x55) as usize]).collect()
+    }
+}
+
+#[cfg(test)]
+mod unit_test {
+    use super::Palette;
+
+    #[test]
+    fn test_to_rgb() {
+        let rgb = Palette::Green.to_rgb(&[0xFF, 0xAA, 0x55, 0x00]);
+        assert_eq!(vec![0x9B, 0xBC, 0x0F, 0x8B, 0xAC, 0x0F, 0x30, 0x62, 0x30, 0x0F, 0x38, 0x0F], rgb);
+        assert_eq!(vec![0xAA; 3], Palette::Gray.to_rgb(&[0xAA]));
+    }
+}
@@ -4,12 +4,22 @@ use crate::hram::HRam;
 use crate::interruputs::{self, Interrupts};
 use crate::wram::WRam;//atode seiri
 use crate::ppu::Ppu;
+use crate::timer::Timer;
+use crate::joypad::Joypad;
+use crate::serial::Serial;
+use crate::dma::Dma;
+use crate::apu::Apu;
 pub struct Peripherals {
     bootrom: Bootrom,
     wram: WRam,
-    cartridge: Cartridge,
+    pub cartridge: Cartridge,
     hram: HRam,
     pub ppu: Ppu,
+    pub timer: Timer,
+    pub joypad: Joypad,
+    pub serial: Serial,
+    dma: Dma,
+    pub apu: Apu,
 }
 impl Peripherals {
     pub fn new(bootrom: Bootrom, cartridge: Cartridge) -> Self {
@@ -19,10 +29,27 @@ impl Peripherals {
             wram: WRam::new(),
             hram: HRam::new(),
             ppu: Ppu::new(),
+            timer: Timer::new(),
+            joypad: Joypad::new(),
+            serial: Serial::new(),
+            dma: Dma::new(),
+            apu: Apu::new(),
         }
     }
 
+    //OAM DMAの転送中、CPUからはHRAMしか見えず、それ以外はDMAが転送中の値が読める
+    fn dma_blocks(&self, addr: u16) -> bool {
+        self.dma.is_transferring() && !(0xFF80..=0xFFFE).contains(&addr)
+    }
+
     pub fn read(&self, interrupts: &Interrupts, addr: u16) -> u8 {
+        if self.dma_blocks(addr) {
+            return self.dma.bus_value;
+        }
+        self.read_bus(interrupts, addr)
+    }
+
+    fn read_bus(&self, interrupts: &Interrupts, addr: u16) -> u8 {
         match addr {
             0x0000..=0x00FF => if self.bootrom.is_active() {
                 self.bootrom.read(addr)
@@ -33,9 +60,14 @@ impl Peripherals {
             0xA000..=0xBFFF => self.cartridge.read(addr),
             0x8000..=0x9FFF => self.ppu.read(addr),
             0xFE00..=0xFE9F => self.ppu.read(addr),
+            0xFF46 => self.dma.read(addr),
             0xFF40..=0xFF4B => self.ppu.read(addr),
             0xC000..=0xFDFF => self.wram.read(addr),
             0xFF80..=0xFFFE => self.hram.read(addr),
+            0xFF04..=0xFF07 => self.timer.read(addr),
+            0xFF00 => self.joypad.read(addr),
+            0xFF01..=0xFF02 => self.serial.read(addr),
+            0xFF10..=0xFF3F => self.apu.read(addr),
             0xFF0F => interrupts.read(addr),
             0xFFFF => interrupts.read(addr),
             _ => 0xFF,
@@ -43,6 +75,9 @@ impl Peripherals {
         }
         
         pub fn write(&mut self, interrupts: &mut Interrupts, addr: u16, val: u8) {
+            if self.dma_blocks(addr) {
+                return;
+            }
             match addr {
                 0x0000..=0x00FF => if !self.bootrom.is_active() {
                     self.cartridge.write(addr, val)
@@ -51,43 +86,84 @@ impl Peripherals {
                 0xA000..=0xBFFF => self.cartridge.write(addr, val),
                 0x8000..=0x9FFF => self.ppu.write(addr, val),
                 0xFE00..=0xFE9F => self.ppu.write(addr, val),
-                // 0xFF40..=0xFF4B => self.ppu.write(addr, val),
-                0xFF40..=0xFF4B => {
-                    println!("I/O WRITE: Addr={:#06x}, Val={:#04x}", addr, val);
-                    self.ppu.write(addr, val);
-                },
+                0xFF46 => self.dma.write(addr, val),
+                0xFF40..=0xFF4B => self.ppu.write(addr, val),
                 0xC000..=0xFDFF => self.wram.write(addr,val),
                 0xFF50          => self.bootrom.write(addr, val),
                 0xFF80..=0xFFFE  => self.hram.write(addr, val),
+                0xFF04..=0xFF07 => self.timer.write(addr, val),
+                0xFF00 => self.joypad.write(interrupts, addr, val),
+                0xFF01..=0xFF02 => self.serial.write(addr, val),
+                0xFF10..=0xFF3F => self.apu.write(addr, val),
                 0xFF0F => interrupts.write(addr, val),
                 0xFFFF => interrupts.write(addr, val),
                 _ => (),
             }
         }
+
+        //1マシンサイクル分OAM DMAを進める
+        pub fn emulate_dma(&mut self, interrupts: &Interrupts) {
+            if let Some((src, offset)) = self.dma.emulate_cycle() {
+                let val = self.read_bus(interrupts, src);
+                self.dma.bus_value = val;
+                self.ppu.write_oam(offset, val);
+            }
+        }
 }
 
 
 #[cfg(test)]
 mod test {
     use crate::bootrom::Bootrom;
+    use crate::cartridge::Cartridge;
+    use crate::cartridge::unit_test::rom;
+    use crate::interruputs::Interrupts;
     use super::Peripherals;
 
+    fn peripherals() -> Peripherals {
+        let bootrom = Bootrom::new(vec![0; 0x100]);
+        Peripherals::new(bootrom, Cartridge::new(rom(0x00, 0x00, 0x00)).unwrap())
+    }
+
     #[test]
     fn test_readwrite_wram() {
-        let mut peri = Peripherals::new(Bootrom::new(vec![0,0]));
-        peri.write(0xC000, 0x42);
-        assert_eq!(0x42, peri.read(0xC000));
+        let mut peri = peripherals();
+        let mut interrupts = Interrupts::default();
+        peri.write(&mut interrupts, 0xC000, 0x42);
+        assert_eq!(0x42, peri.read(&interrupts, 0xC000));
     }
     #[test]
     fn test_readwrite_hram() {
-        let mut peri = Peripherals::new(Bootrom::new(vec![0,0]));
-        peri.write(0xFF80, 0x42);
-        assert_eq!(0x42, peri.read(0xFF80));
+        let mut peri = peripherals();
+        let mut interrupts = Interrupts::default();
+        peri.write(&mut interrupts, 0xFF80, 0x42);
+        assert_eq!(0x42, peri.read(&interrupts, 0xFF80));
     }
     #[test]
     fn test_readwrite_bootrom() {
-        let mut peri = Peripherals::new(Bootrom::new(vec![0,0]));
-        peri.write(0xFF50, 1);
-        assert_eq!(false, peri.bootrom.is_active());
+        let mut peri = peripherals();
+        let mut interrupts = Interrupts::default();
+        peri.write(&mut interrupts, 0xFF50, 1);
+        assert!(!peri.bootrom.is_active());
     }
-}
\ No newline at end of file
+    #[test]
+    fn test_dma_blocks_io() {
+        let mut peri = peripherals();
+        let mut interrupts = Interrupts::default();
+        peri.write(&mut interrupts, 0xC000, 0x42);
+        peri.write(&mut interrupts, 0xFF80, 0x99);
+        peri.write(&mut interrupts, 0xFF46, 0xC0);
+        //準備の1サイクルの後、0xC000を転送する
+        peri.emulate_dma(&interrupts);
+        peri.emulate_dma(&interrupts);
+        //I/OレジスタもDMAが転送中の値が読め、HRAMだけが見える
+        assert_eq!(0x42, peri.read(&interrupts, 0xFF44));
+        peri.write(&mut interrupts, 0xFF47, 0x12);
+        assert_eq!(0x99, peri.read(&interrupts, 0xFF80));
+        for _ in 0..0xA0 {
+            peri.emulate_dma(&interrupts);
+        }
+        assert_eq!(0x00, peri.read(&interrupts, 0xFF44));
+        assert_eq!(0x00, peri.read(&interrupts, 0xFF47));
+    }
+}
@@ -1,4 +1,4 @@
-use std::iter;
+use crate::interruputs::{self, Interrupts};
 
 pub const LCD_WIDTH: usize = 160;
 pub const LCD_HEIGHT: usize = 144;
@@ -11,6 +11,15 @@ enum Mode {
     OamScan = 2,
     Drawing = 3,
 }
+
+//OAMの1エントリ(4バイト)
+#[derive(Copy, Clone)]
+struct Sprite {
+    y: u8, //画面上の位置+16
+    x: u8, //画面上の位置+8
+    tile_idx: u8,
+    flags: u8,
+}
 pub struct Ppu { //
     mode: Mode,
     lcdc: u8, //0xFF40
@@ -27,6 +36,11 @@ pub struct Ppu { //
     vram: Box<[u8; 0x2000]>, //0x8000~0x9FFF
     oam: Box<[u8; 0xA0]>, //0xFE00~0xFE9F
     pub buffer: Vec<u8>,
+    bg_line: [u8; LCD_WIDTH], //今のラインのBG/ウィンドウのカラー番号 OBJとの優先度判定用
+    sprites: Vec<Sprite>, //今のラインに表示するOBJ 優先度順
+    window_line: u8, //ウィンドウの内部ラインカウンタ 描画したラインだけ進む
+    wy_triggered: bool, //このフレームでLY==WYになったか
+    stat_line: bool, //STAT割り込みの各要因のOR 立ち上がりで割り込み
     cycles: u8,
 }
 //LCDC Register
@@ -46,6 +60,14 @@ const VBLANK_INT: u8 = 1 << 4;
 const HBLANK_INT: u8 = 1 << 3;
 const LYC_EQ_LY: u8 = 1 << 2;
 
+//OAM flags
+const OBJ_BG_PRIORITY: u8 = 1 << 7; //BGのカラー1~3の後ろに表示
+const Y_FLIP: u8 = 1 << 6;
+const X_FLIP: u8 = 1 << 5;
+const OBJ_PALETTE: u8 = 1 << 4; //0:OBP0 1:OBP1
+
+const MAX_SPRITES_PER_LINE: usize = 10;
+
 impl Ppu {
     pub fn new() -> Self {
         Self {
@@ -64,6 +86,11 @@ impl Ppu {
             vram: Box::new([0; 0x2000]),
             oam: Box::new([0; 0xA0]),
             buffer: vec![0; LCD_PIXELS*4],
+            bg_line: [0; LCD_WIDTH],
+            sprites: Vec::with_capacity(MAX_SPRITES_PER_LINE),
+            window_line: 0,
+            wy_triggered: false,
+            stat_line: false,
             cycles: 20,
         }
     }
@@ -109,7 +136,13 @@ impl Ppu {
             0xFF42 => self.scy = val,
             0xFF43 => self.scx = val,
             0xFF44 => {},
-            0xFF45 => self.lyc = val,
+            //LYCを今のLYに合わせてもLY=LYCになる
+            0xFF45 => {
+                self.lyc = val;
+                if self.lcdc & PPU_ENABLE > 0 {
+                    self.check_lyc_eq_ly();
+                }
+            },
             0xFF47 => self.bgp = val,
             0xFF48 => self.obp0 = val,
             0xFF49 => self.obp1 = val,
@@ -119,6 +152,11 @@ impl Ppu {
         }
     }
 
+    //OAM DMAはモードに関係なく書き込める
+    pub fn write_oam(&mut self, offset: usize, val: u8) {
+        self.oam[offset] = val;
+    }
+
     fn get_pixel_from_tile(&self, tile_idx: usize, row: u8, col: u8) -> u8 {
         let r = (row * 2) as usize;
         let c = (7 - col) as usize;
@@ -138,8 +176,21 @@ impl Ppu {
         }
     }
 
+    //パレットからカラー番号の明るさを得る
+    fn get_color(palette: u8, pixel: u8) -> u8 {
+        match (palette >> (pixel << 1)) & 0b11 {
+            0b00 => 0xFF,
+            0b01 => 0xAA,
+            0b10 => 0x55,
+            _    => 0x00,
+        }
+    }
+
     fn render_bg(&mut self) {
+        //DMGではBG/ウィンドウが無効だと白になる
         if self.lcdc & BG_WINDOW_ENABLE == 0 {
+            self.bg_line = [0; LCD_WIDTH];
+            self.buffer[LCD_WIDTH * self.ly as usize..LCD_WIDTH * (self.ly as usize + 1)].fill(0xFF);
             return;
         }
         let y = self.ly.wrapping_add(self.scy);
@@ -154,13 +205,97 @@ impl Ppu {
 
             let pixel = self.get_pixel_from_tile(tile_idx, y & 7, x & 7);
 
-            self.buffer[LCD_WIDTH * self.ly as usize + i] =
-                match (self.bgp >> (pixel << 1)) & 0b11 {
-                    0b00 => 0xFF,
-                    0b01 => 0xAA,
-                    0b10 => 0x55,
-                    _    => 0x00,
-                };
+            self.bg_line[i] = pixel;
+            self.buffer[LCD_WIDTH * self.ly as usize + i] = Self::get_color(self.bgp, pixel);
+        }
+    }
+
+    //WX=7で左端 WX<7だとウィンドウの左側が画面外にはみ出す WX>166では表示されない
+    fn render_window(&mut self) {
+        if self.lcdc & BG_WINDOW_ENABLE == 0 || self.lcdc & WINDOW_ENABLE == 0 {
+            return;
+        }
+        if !self.wy_triggered || self.wx > 166 {
+            return;
+        }
+        let y = self.window_line;
+        let start = self.wx.saturating_sub(7) as usize;
+        for i in start..LCD_WIDTH {
+            let x = (i + 7 - self.wx as usize) as u8;
+
+            let tile_idx = self.get_tile_idx_from_tile_map(
+                self.lcdc & WINDOW_TILE_MAP > 0,
+                y >> 3,
+                x >> 3
+            );
+
+            let pixel = self.get_pixel_from_tile(tile_idx, y & 7, x & 7);
+
+            self.bg_line[i] = pixel;
+            self.buffer[LCD_WIDTH * self.ly as usize + i] = Self::get_color(self.bgp, pixel);
+        }
+        self.window_line += 1;
+    }
+
+    //今のラインに掛かるOBJをOAMの先頭から最大10個まで拾う
+    fn scan_oam(&mut self) {
+        self.sprites.clear();
+        let height = if self.lcdc & SPRITE_SIZE > 0 { 16 } else { 8 };
+        let line = self.ly as u16 + 16;
+        for entry in self.oam.chunks(4) {
+            let y = entry[0] as u16;
+            if line >= y && line < y + height {
+                self.sprites.push(Sprite {
+                    y: entry[0],
+                    x: entry[1],
+                    tile_idx: entry[2],
+                    flags: entry[3],
+                });
+                if self.sprites.len() == MAX_SPRITES_PER_LINE {
+                    break;
+                }
+            }
+        }
+        //DMGではXが小さい方が優先、同じならOAMの若い方(安定ソートなので順番が保たれる)
+        self.sprites.sort_by_key(|sprite| sprite.x);
+    }
+
+    fn render_sprites(&mut self) {
+        if self.lcdc & SPRITE_ENABLE == 0 {
+            return;
+        }
+        let tall = self.lcdc & SPRITE_SIZE > 0;
+        let mut drawn = [false; LCD_WIDTH];
+        for sprite in self.sprites.iter() {
+            let mut row = self.ly.wrapping_add(16).wrapping_sub(sprite.y);
+            if sprite.flags & Y_FLIP > 0 {
+                row = if tall { 15 - row } else { 7 - row };
+            }
+            //8x16のときはタイル番号のbit0を無視して上下2枚のタイルを使う
+            let tile_idx = if tall {
+                (sprite.tile_idx & 0xFE) as usize + (row >> 3) as usize
+            } else {
+                sprite.tile_idx as usize
+            };
+            let palette = if sprite.flags & OBJ_PALETTE > 0 { self.obp1 } else { self.obp0 };
+            for col in 0..8u8 {
+                let i = sprite.x as usize + col as usize;
+                if !(8..LCD_WIDTH + 8).contains(&i) || drawn[i - 8] {
+                    continue;
+                }
+                let i = i - 8;
+                let c = if sprite.flags & X_FLIP > 0 { 7 - col } else { col };
+                let pixel = self.get_pixel_from_tile(tile_idx, row & 7, c);
+                //カラー0は透明 優先度の低いOBJが見えるようにする
+                if pixel == 0 {
+                    continue;
+                }
+                drawn[i] = true;
+                if sprite.flags & OBJ_BG_PRIORITY > 0 && self.bg_line[i] != 0 {
+                    continue;
+                }
+                self.buffer[LCD_WIDTH * self.ly as usize + i] = Self::get_color(palette, pixel);
+            }
         }
     }
 
@@ -172,13 +307,27 @@ impl Ppu {
         }
     }
 
-    pub fn emulate_cycle(&mut self) -> bool {
+    //要因のどれかが有効な間にもう一つ有効になっても割り込みは起きない(STAT blocking)
+    fn update_stat_interrupt(&mut self, interrupts: &mut Interrupts) {
+        let line = (self.stat & LYC_EQ_LY_INT > 0 && self.stat & LYC_EQ_LY > 0)
+            || (self.stat & HBLANK_INT > 0 && self.mode == Mode::HBlank)
+            || (self.stat & VBLANK_INT > 0 && self.mode == Mode::VBlank)
+            || (self.stat & OAM_SCAN_INT > 0 && self.mode == Mode::OamScan);
+        if line && !self.stat_line {
+            interrupts.irq(interruputs::STAT);
+        }
+        self.stat_line = line;
+    }
+
+    pub fn emulate_cycle(&mut self, interrupts: &mut Interrupts) -> bool {
         if self.lcdc & PPU_ENABLE == 0 {
             return false;
         }
 
         self.cycles -= 1;
         if self.cycles > 0 {
+            //STATやLYCへの書き込みでも立ち上がりが起きうる
+            self.update_stat_interrupt(interrupts);
             return false;
         }
 
@@ -191,7 +340,8 @@ impl Ppu {
                     self.cycles = 20;
                 } else {
                     self.mode = Mode::VBlank;
-                    self.cycles = 144;
+                    self.cycles = 114;
+                    interrupts.irq(interruputs::VBLANK);
                 }
                 self.check_lyc_eq_ly();
             },
@@ -200,30 +350,246 @@ impl Ppu {
                 if self.ly > 153 {
                     ret = true;
                     self.ly = 0;
+                    self.window_line = 0;
+                    self.wy_triggered = false;
                     self.mode = Mode::OamScan;
                     self.cycles = 20;
                 } else {
-                    self.cycles = 144;
+                    self.cycles = 114;
                 }
                 self.check_lyc_eq_ly();
             },
             Mode::OamScan => {
+                //WYの一致はライン毎に確認され、一度一致したらそのフレームの間は有効
+                if self.ly == self.wy {
+                    self.wy_triggered = true;
+                }
+                self.scan_oam();
                 self.mode = Mode::Drawing;
                 self.cycles = 43;
             },
             Mode::Drawing => {
                 self.render_bg();
+                self.render_window();
+                self.render_sprites();
                 self.mode = Mode::HBlank;
                 self.cycles = 51;
             },
         }
+        self.update_stat_interrupt(interrupts);
         ret
     }
 
-    pub fn pixel_buffer(&self) -> Box<[u8]> {
-        self.buffer.iter().flat_map(
-            |&e| iter::repeat(e.into()).take(3)
-        ).collect::<Box<[u8]>>()
+}
+
+#[cfg(test)]
+mod unit_test {
+    use super::*;
+
+    //タイル1を全ピクセルcolorで塗る
+    fn fill_tile(ppu: &mut Ppu, tile_idx: usize, color: u8) {
+        for r in 0..8 {
+            ppu.vram[tile_idx * 16 + r * 2] = if color & 1 > 0 { 0xFF } else { 0x00 };
+            ppu.vram[tile_idx * 16 + r * 2 + 1] = if color & 2 > 0 { 0xFF } else { 0x00 };
+        }
+    }
+
+    fn render_line(ppu: &mut Ppu, ly: u8) {
+        ppu.ly = ly;
+        if ppu.ly == ppu.wy {
+            ppu.wy_triggered = true;
+        }
+        ppu.scan_oam();
+        ppu.render_bg();
+        ppu.render_window();
+        ppu.render_sprites();
+    }
+
+    fn ppu() -> Ppu {
+        let mut ppu = Ppu::new();
+        ppu.lcdc = PPU_ENABLE | TILE_DATA_ADDRESSING_MODE | SPRITE_ENABLE | BG_WINDOW_ENABLE;
+        ppu.bgp = 0b11_10_01_00;
+        ppu.obp0 = 0b11_10_01_00;
+        ppu.obp1 = 0b00_00_00_00;
+        fill_tile(&mut ppu, 1, 3);
+        ppu
+    }
+
+    #[test]
+    fn test_sprite() {
+        let mut ppu = ppu();
+        ppu.oam[0..4].copy_from_slice(&[16, 8 + 10, 1, 0]);
+        render_line(&mut ppu, 0);
+        assert_eq!(0xFF, ppu.buffer[9]);
+        assert_eq!(0x00, ppu.buffer[10]);
+        assert_eq!(0x00, ppu.buffer[17]);
+        assert_eq!(0xFF, ppu.buffer[18]);
+        render_line(&mut ppu, 8);
+        assert_eq!(0xFF, ppu.buffer[LCD_WIDTH * 8 + 10]);
+    }
+
+    #[test]
+    fn test_sprite_limit_and_priority() {
+        let mut ppu = ppu();
+        ppu.obp1 = 0b01_01_01_01;
+        //OAMの先頭はOBP1(灰色)でX=4、2番目はOBP0(黒)でX=0
+        ppu.oam[0..4].copy_from_slice(&[16, 8 + 4, 1, OBJ_PALETTE]);
+        ppu.oam[4..8].copy_from_slice(&[16, 8, 1, 0]);
+        for i in 2..11 {
+            ppu.oam[i * 4..i * 4 + 4].copy_from_slice(&[16, 8 + 10 * i as u8, 1, 0]);
+        }
+        render_line(&mut ppu, 0);
+        assert_eq!(10, ppu.sprites.len());
+        //OAMの順番よりXが小さい方が優先
+        assert_eq!(0x00, ppu.buffer[4]);
+        assert_eq!(0xAA, ppu.buffer[8]);
+        //11個目以降は表示されない
+        assert_eq!(0x00, ppu.buffer[90]);
+        assert_eq!(0xFF, ppu.buffer[100]);
+    }
+
+    #[test]
+    fn test_bg_priority() {
+        let mut ppu = ppu();
+        fill_tile(&mut ppu, 2, 1);
+        ppu.vram[0x1800] = 2;
+        ppu.oam[0..4].copy_from_slice(&[16, 8, 1, OBJ_BG_PRIORITY]);
+        ppu.oam[4..8].copy_from_slice(&[16, 16, 1, OBJ_BG_PRIORITY]);
+        render_line(&mut ppu, 0);
+        //BGのカラー1の後ろに隠れる
+        assert_eq!(0xAA, ppu.buffer[0]);
+        //BGのカラー0の上には表示される
+        assert_eq!(0x00, ppu.buffer[8]);
+    }
+
+    #[test]
+    fn test_tall_sprite_flip() {
+        let mut ppu = ppu();
+        ppu.lcdc |= SPRITE_SIZE;
+        fill_tile(&mut ppu, 2, 1);
+        //タイル3(下半分)は空、Y反転で上に来る
+        ppu.oam[0..4].copy_from_slice(&[16, 8, 3, Y_FLIP]);
+        render_line(&mut ppu, 0);
+        assert_eq!(0xFF, ppu.buffer[0]);
+        render_line(&mut ppu, 15);
+        assert_eq!(0xAA, ppu.buffer[LCD_WIDTH * 15]);
+    }
+
+    #[test]
+    fn test_window() {
+        let mut ppu = ppu();
+        ppu.lcdc |= WINDOW_ENABLE | WINDOW_TILE_MAP;
+        ppu.vram[0x1C00] = 1;
+        ppu.wy = 2;
+        ppu.wx = 7 + 10;
+        render_line(&mut ppu, 1);
+        assert_eq!(0xFF, ppu.buffer[LCD_WIDTH + 10]);
+        render_line(&mut ppu, 2);
+        assert_eq!(0xFF, ppu.buffer[LCD_WIDTH * 2 + 9]);
+        assert_eq!(0x00, ppu.buffer[LCD_WIDTH * 2 + 10]);
+        assert_eq!(1, ppu.window_line);
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn test_window_line_counter() {
+        let mut ppu = ppu();
+        ppu.lcdc |= WINDOW_ENABLE;
+        fill_tile(&mut ppu, 2, 2);
+        ppu.vram[0x1800] = 1;
+        ppu.vram[0x1820] = 2;
+        ppu.wy = 0;
+        ppu.wx = 7;
+        for ly in 0..4 {
+            render_line(&mut ppu, ly);
+        }
+        //途中でウィンドウを画面外に出すとカウンタは止まる
+        ppu.wx = 200;
+        for ly in 4..10 {
+            render_line(&mut ppu, ly);
+        }
+        assert_eq!(4, ppu.window_line);
+        //WYを変えても一度一致していれば表示は続き、カウンタの続きから描く
+        ppu.wx = 7;
+        ppu.wy = 100;
+        for ly in 10..15 {
+            render_line(&mut ppu, ly);
+        }
+        assert_eq!(0x00, ppu.buffer[LCD_WIDTH * 13]);
+        assert_eq!(0x55, ppu.buffer[LCD_WIDTH * 14]);
+    }
+
+    #[test]
+    fn test_window_wx_under_7() {
+        let mut ppu = ppu();
+        ppu.lcdc |= WINDOW_ENABLE;
+        ppu.vram[0x1801] = 1;
+        ppu.wy = 0;
+        ppu.wx = 3;
+        render_line(&mut ppu, 0);
+        //ウィンドウのX=4が画面の左端に来る
+        assert_eq!(0xFF, ppu.buffer[3]);
+        assert_eq!(0x00, ppu.buffer[4]);
+    }
+
+    fn run_until_ly(ppu: &mut Ppu, interrupts: &mut Interrupts, ly: u8) {
+        while ppu.ly != ly {
+            ppu.emulate_cycle(interrupts);
+        }
+    }
+
+    #[test]
+    fn test_vblank_interrupt() {
+        let mut ppu = ppu();
+        let mut interrupts = Interrupts::default();
+        run_until_ly(&mut ppu, &mut interrupts, 143);
+        assert_eq!(0, interrupts.int_flags & interruputs::VBLANK);
+        run_until_ly(&mut ppu, &mut interrupts, 144);
+        assert!(interrupts.int_flags & interruputs::VBLANK > 0);
+        assert!(interrupts.int_flags & interruputs::STAT == 0);
+    }
+
+    #[test]
+    fn test_stat_interrupt() {
+        let mut ppu = ppu();
+        let mut interrupts = Interrupts::default();
+        ppu.write(0xFF45, 10);
+        ppu.write(0xFF41, LYC_EQ_LY_INT);
+        run_until_ly(&mut ppu, &mut interrupts, 10);
+        assert!(interrupts.int_flags & interruputs::STAT > 0);
+    }
+
+    #[test]
+    fn test_stat_blocking() {
+        let mut ppu = ppu();
+        let mut interrupts = Interrupts::default();
+        ppu.write(0xFF45, 10);
+        ppu.write(0xFF41, LYC_EQ_LY_INT | OAM_SCAN_INT | HBLANK_INT);
+        run_until_ly(&mut ppu, &mut interrupts, 9);
+        while ppu.mode != Mode::HBlank {
+            ppu.emulate_cycle(&mut interrupts);
+        }
+        //HBlankからLY=LYCとOAMスキャンが続くのでラインが下がらず割り込みは起きない
+        interrupts.int_flags = 0;
+        run_until_ly(&mut ppu, &mut interrupts, 10);
+        while ppu.mode != Mode::Drawing {
+            ppu.emulate_cycle(&mut interrupts);
+        }
+        assert_eq!(0, interrupts.int_flags & interruputs::STAT);
+    }
+
+    #[test]
+    fn test_lyc_write() {
+        let mut ppu = ppu();
+        let mut interrupts = Interrupts::default();
+        ppu.write(0xFF45, 10);
+        ppu.write(0xFF41, LYC_EQ_LY_INT);
+        run_until_ly(&mut ppu, &mut interrupts, 5);
+        ppu.emulate_cycle(&mut interrupts);
+        interrupts.int_flags = 0;
+        //ラインの途中でLYCを今のLYにすると次のサイクルで割り込みが起きる
+        ppu.write(0xFF45, 5);
+        assert!(ppu.read(0xFF41) & LYC_EQ_LY > 0);
+        ppu.emulate_cycle(&mut interrupts);
+        assert!(interrupts.int_flags & interruputs::STAT > 0);
+    }
+}
@@ -0,0 +1,300 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+//RTCを進めるための時計 テストでは差し替えて時間を自由に進める
+pub trait ClockSource {
+    //秒単位の現在時刻
+    fn now(&self) -> u64;
+}
+
+//PCの時計
+pub struct SystemClock;
+impl ClockSource for SystemClock {
+    fn now(&self) -> u64 {
+        SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+    }
+}
+
+//RTCのレジスタ番号(0x4000~0x5FFFに書き込む値)
+pub const RTC_S: u8 = 0x08;
+pub const RTC_M: u8 = 0x09;
+pub const RTC_H: u8 = 0x0A;
+pub const RTC_DL: u8 = 0x0B;
+pub const RTC_DH: u8 = 0x0C;
+//DHのビット
+const DH_DAY_HIGH: u8 = 1 << 0;
+const DH_HALT: u8 = 1 << 6;
+const DH_CARRY: u8 = 1 << 7;
+//レジスタごとに使われるビット
+const MASKS: [u8; 5] = [0x3F, 0x3F, 0x1F, 0xFF, DH_CARRY | DH_HALT | DH_DAY_HIGH];
+//セーブデータの末尾に付ける大きさ(BGBなどと同じ形式)
+//今のレジスタと、ラッチしたレジスタをそれぞれ4バイトずつ、最後に保存した時刻を8バイト
+pub const SAVE_SIZE: usize = 48;
+
+//MBC3の時計 読み出しはラッチした値を返す
+pub struct Rtc {
+    clock: Box<dyn ClockSource>,
+    last: u64, //最後に進めたときのclockの時刻
+    seconds: u8,
+    minutes: u8,
+    hours: u8,
+    days: u16, //9bit
+    halt: bool,
+    carry: bool, //日数が511を超えた
+    latched: [u8; 5],
+    latch_ready: bool, //0x00が書き込まれ、次に0x01が来たらラッチする
+}
+impl Rtc {
+    pub fn new(clock: Box<dyn ClockSource>) -> Self {
+        let last = clock.now();
+        Self {
+            clock,
+            last,
+            seconds: 0,
+            minutes: 0,
+            hours: 0,
+            days: 0,
+            halt: false,
+            carry: false,
+            latched: [0; 5],
+            latch_ready: false,
+        }
+    }
+
+    //ここまでの時間は前の時計で進めておく
+    pub fn set_clock_source(&mut self, clock: Box<dyn ClockSource>) {
+        self.update();
+        self.last = clock.now();
+        self.clock = clock;
+    }
+
+    fn registers(&self) -> [u8; 5] {
+        [
+            self.seconds,
+            self.minutes,
+            self.hours,
+            self.days as u8,
+            ((self.days >> 8) as u8 & DH_DAY_HIGH) | (DH_HALT * self.halt as u8) | (DH_CARRY * self.carry as u8),
+        ]
+    }
+
+    //前回からの経過時間だけ進める 止まっている間は進めない
+    fn update(&mut self) {
+        let now = self.clock.now();
+        if !self.halt && now > self.last {
+            self.advance(now - self.last);
+        }
+        self.last = now;
+    }
+
+    fn advance(&mut self, mut secs: u64) {
+        //範囲外の値が書き込まれているときは1秒ずつ進める(桁上がりせずに0に戻る)
+        while secs > 0 && (self.seconds >= 60 || self.minutes >= 60 || self.hours >= 24) {
+            self.tick();
+            secs -= 1;
+        }
+        let total = ((self.days as u64 * 24 + self.hours as u64) * 60 + self.minutes as u64) * 60
+            + self.seconds as u64 + secs;
+        self.seconds = (total % 60) as u8;
+        self.minutes = (total / 60 % 60) as u8;
+        self.hours = (total / 3600 % 24) as u8;
+        let days = total / 86400;
+        if days >= 512 {
+            self.carry = true;
+        }
+        self.days = (days % 512) as u16;
+    }
+
+    fn tick(&mut self) {
+        self.seconds = (self.seconds + 1) & MASKS[0];
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+        self.minutes = (self.minutes + 1) & MASKS[1];
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+        self.hours = (self.hours + 1) & MASKS[2];
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+        self.days = (self.days + 1) & 0x1FF;
+        if self.days == 0 {
+            self.carry = true;
+        }
+    }
+
+    //セーブデータの末尾に付ける形式で書き出す
+    pub fn save(&mut self) -> [u8; SAVE_SIZE] {
+        self.update();
+        let mut data = [0; SAVE_SIZE];
+        for (i, val) in self.registers().into_iter().chain(self.latched).enumerate() {
+            data[i * 4] = val;
+        }
+        data[40..48].copy_from_slice(&self.last.to_le_bytes());
+        data
+    }
+
+    //保存した時刻から今までの分を進める 時刻が4バイトの形式(44バイト)も読める
+    pub fn load(&mut self, data: &[u8]) {
+        if data.len() < 44 {
+            return;
+        }
+        let reg = |i: usize| data[i * 4];
+        self.seconds = reg(0) & MASKS[0];
+        self.minutes = reg(1) & MASKS[1];
+        self.hours = reg(2) & MASKS[2];
+        self.days = reg(3) as u16 | ((reg(4) & DH_DAY_HIGH) as u16) << 8;
+        self.halt = reg(4) & DH_HALT > 0;
+        self.carry = reg(4) & DH_CARRY > 0;
+        for (i, mask) in MASKS.iter().enumerate() {
+            self.latched[i] = reg(5 + i) & mask;
+        }
+        self.last = if data.len() >= SAVE_SIZE {
+            u64::from_le_bytes(data[40..48].try_into().unwrap())
+        } else {
+            u32::from_le_bytes(data[40..44].try_into().unwrap()) as u64
+        };
+        self.update();
+    }
+
+    //0x6000~0x7FFFへの書き込み 0x00, 0x01の順で書くと今の時刻をラッチする
+    pub fn write_latch(&mut self, val: u8) {
+        if self.latch_ready && val == 0x01 {
+            self.update();
+            self.latched = self.registers();
+        }
+        self.latch_ready = val == 0x00;
+    }
+
+    pub fn read(&self, reg: u8) -> u8 {
+        self.latched[(reg - RTC_S) as usize]
+    }
+
+    pub fn write(&mut self, reg: u8, val: u8) {
+        self.update();
+        let val = val & MASKS[(reg - RTC_S) as usize];
+        match reg {
+            RTC_S => self.seconds = val,
+            RTC_M => self.minutes = val,
+            RTC_H => self.hours = val,
+            RTC_DL => self.days = self.days & 0x100 | val as u16,
+            RTC_DH => {
+                self.days = self.days & 0xFF | ((val & DH_DAY_HIGH) as u16) << 8;
+                self.halt = val & DH_HALT > 0;
+                self.carry = val & DH_CARRY > 0;
+            },
+            _ => unreachable!(),
+        }
+        self.latched[(reg - RTC_S) as usize] = val;
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod unit_test {
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use super::{ClockSource, Rtc, RTC_S, RTC_M, RTC_H, RTC_DL, RTC_DH};
+
+    //テストから進める時計
+    #[derive(Clone, Default)]
+    pub(crate) struct MockClock(pub(crate) Rc<Cell<u64>>);
+    impl ClockSource for MockClock {
+        fn now(&self) -> u64 {
+            self.0.get()
+        }
+    }
+    impl MockClock {
+        pub(crate) fn advance(&self, secs: u64) {
+            self.0.set(self.0.get() + secs);
+        }
+    }
+
+    fn latch(rtc: &mut Rtc) {
+        rtc.write_latch(0x00);
+        rtc.write_latch(0x01);
+    }
+
+    #[test]
+    fn test_latch() {
+        let clock = MockClock::default();
+        let mut rtc = Rtc::new(Box::new(clock.clone()));
+        clock.advance(3 * 3600 + 2 * 60 + 1);
+        //ラッチするまで読める値は変わらない
+        assert_eq!(0, rtc.read(RTC_S));
+        latch(&mut rtc);
+        assert_eq!([1, 2, 3], [rtc.read(RTC_S), rtc.read(RTC_M), rtc.read(RTC_H)]);
+        clock.advance(1);
+        assert_eq!(1, rtc.read(RTC_S));
+        //0x00を挟まなければラッチしない
+        rtc.write_latch(0x01);
+        assert_eq!(1, rtc.read(RTC_S));
+        latch(&mut rtc);
+        assert_eq!(2, rtc.read(RTC_S));
+    }
+
+    #[test]
+    fn test_halt() {
+        let clock = MockClock::default();
+        let mut rtc = Rtc::new(Box::new(clock.clone()));
+        rtc.write(RTC_DH, 0x40);
+        clock.advance(100);
+        latch(&mut rtc);
+        assert_eq!(0, rtc.read(RTC_S));
+        assert_eq!(0x40, rtc.read(RTC_DH));
+        rtc.write(RTC_S, 30);
+        rtc.write(RTC_DH, 0x00);
+        clock.advance(5);
+        latch(&mut rtc);
+        assert_eq!(35, rtc.read(RTC_S));
+    }
+
+    #[test]
+    fn test_day_carry() {
+        let clock = MockClock::default();
+        let mut rtc = Rtc::new(Box::new(clock.clone()));
+        rtc.write(RTC_DL, 0xFF);
+        rtc.write(RTC_DH, 0x01);
+        rtc.write(RTC_H, 23);
+        rtc.write(RTC_M, 59);
+        rtc.write(RTC_S, 59);
+        clock.advance(1);
+        latch(&mut rtc);
+        assert_eq!([0, 0, 0, 0x00], [rtc.read(RTC_S), rtc.read(RTC_M), rtc.read(RTC_H), rtc.read(RTC_DL)]);
+        //日数は0に戻り、キャリーが立つ
+        assert_eq!(0x80, rtc.read(RTC_DH));
+    }
+
+    #[test]
+    fn test_save_load() {
+        let clock = MockClock::default();
+        clock.advance(1_000_000);
+        let mut rtc = Rtc::new(Box::new(clock.clone()));
+        rtc.write(RTC_H, 5);
+        latch(&mut rtc);
+        let data = rtc.save();
+        //電源を切っている間の時間も進む
+        clock.advance(3600);
+        let mut loaded = Rtc::new(Box::new(clock.clone()));
+        loaded.load(&data);
+        assert_eq!(5, loaded.read(RTC_H));
+        latch(&mut loaded);
+        assert_eq!(6, loaded.read(RTC_H));
+    }
+
+    #[test]
+    fn test_invalid_value() {
+        let clock = MockClock::default();
+        let mut rtc = Rtc::new(Box::new(clock.clone()));
+        //範囲外の秒は63の次に0になり、分は進まない
+        rtc.write(RTC_S, 62);
+        clock.advance(3);
+        latch(&mut rtc);
+        assert_eq!([1, 0], [rtc.read(RTC_S), rtc.read(RTC_M)]);
+        //書き込めないビットは落ちる
+        rtc.write(RTC_M, 0xFF);
+        assert_eq!(0x3F, rtc.read(RTC_M));
+    }
+}
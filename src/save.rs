@@ -0,0 +1,138 @@
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use crate::gameboy::GameBoy;
+
+//SRAMが書き換えられていれば、この間隔で保存する
+pub const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+//電池でバックアップされたSRAM(とRTC)を保存するファイル
+pub struct SaveFile {
+    path: PathBuf,
+    interval: Duration,
+    last_save: Instant,
+}
+impl SaveFile {
+    pub fn new(path: impl Into<PathBuf>, interval: Duration) -> Self {
+        Self {
+            path: path.into(),
+            interval,
+            last_save: Instant::now(),
+        }
+    }
+
+    //ROMと同じ場所の拡張子を.savにしたファイル
+    pub fn for_rom(rom: impl AsRef<Path>) -> Self {
+        Self::new(rom.as_ref().with_extension("sav"), AUTOSAVE_INTERVAL)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    //ファイルがまだ無ければ何もしない
+    pub fn load(&self, gb: &mut GameBoy) -> io::Result<()> {
+        if !gb.has_battery() {
+            return Ok(());
+        }
+        match fs::read(&self.path) {
+            Ok(data) => gb.load_save_data(&data),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => return Err(e),
+        }
+        Ok(())
+    }
+
+    pub fn save(&mut self, gb: &mut GameBoy) -> io::Result<()> {
+        self.last_save = Instant::now();
+        if !gb.has_battery() {
+            return Ok(());
+        }
+        write_atomic(&self.path, &gb.save_data())
+    }
+
+    //毎フレーム呼ぶ 前回からinterval経っていて、SRAMが書き換えられていれば保存する
+    pub fn autosave(&mut self, gb: &mut GameBoy) -> io::Result<()> {
+        if self.last_save.elapsed() < self.interval || !gb.is_save_dirty() {
+            return Ok(());
+        }
+        self.save(gb)
+    }
+}
+
+//一時ファイルに書き切ってから置き換えるので、途中で落ちても前のファイルが残る
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut tmp = OsString::from(path);
+    tmp.push(".tmp");
+    let tmp = PathBuf::from(tmp);
+    let mut file = File::create(&tmp)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp, path)
+}
+
+#[cfg(test)]
+mod unit_test {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::Duration;
+    use super::{SaveFile, write_atomic};
+    use crate::bootrom::Bootrom;
+    use crate::cartridge::Cartridge;
+    use crate::cartridge::unit_test::rom;
+    use crate::gameboy::GameBoy;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("gbemu-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn gameboy(cartridge_type: u8) -> GameBoy {
+        GameBoy::new(Bootrom::disabled(), Cartridge::new(rom(cartridge_type, 0x00, 0x02)).unwrap())
+    }
+
+    #[test]
+    fn test_write_atomic() {
+        let dir = temp_dir("atomic");
+        let path = dir.join("game.sav");
+        write_atomic(&path, b"old").unwrap();
+        write_atomic(&path, b"new").unwrap();
+        assert_eq!(b"new", &fs::read(&path).unwrap()[..]);
+        //一時ファイルは残らない
+        assert_eq!(1, fs::read_dir(&dir).unwrap().count());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_save_load() {
+        let dir = temp_dir("save");
+        let mut save = SaveFile::new(dir.join("game.sav"), Duration::ZERO);
+        assert_eq!(dir.join("game.sav"), SaveFile::for_rom(dir.join("game.gb")).path());
+        let mut gb = gameboy(0x03);
+        //ファイルが無ければそのまま
+        save.load(&mut gb).unwrap();
+        //書き換えられるまで保存しない
+        save.autosave(&mut gb).unwrap();
+        assert!(!save.path().exists());
+        gb.peripherals.cartridge.write(0x0000, 0x0A);
+        gb.peripherals.cartridge.write(0xA123, 0x99);
+        save.autosave(&mut gb).unwrap();
+        assert_eq!(0x2000, fs::read(save.path()).unwrap().len());
+
+        let mut loaded = gameboy(0x03);
+        save.load(&mut loaded).unwrap();
+        loaded.peripherals.cartridge.write(0x0000, 0x0A);
+        assert_eq!(0x99, loaded.peripherals.cartridge.read(0xA123));
+
+        //電池が無ければ保存しない
+        let mut save = SaveFile::new(dir.join("nobattery.sav"), Duration::ZERO);
+        save.save(&mut gameboy(0x02)).unwrap();
+        assert!(!save.path().exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
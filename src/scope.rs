@@ -0,0 +1,108 @@
+use crate::ppu::{LCD_WIDTH, LCD_HEIGHT, LCD_PIXELS};
+
+//チャンネルごとに残す出力の数 SCOPE_INTERVALごとに1つ(約16ms分)
+const HISTORY: usize = 1024;
+//この間隔(マシンサイクル)ごとに出力を記録する
+pub const SCOPE_INTERVAL: u32 = 16;
+//1画素あたりの記録の数
+const STEP: usize = 4;
+const LANE_HEIGHT: usize = LCD_HEIGHT / 4;
+
+//4チャンネルそれぞれの最近の出力を残しておき、オシロスコープのように描く
+pub struct Oscilloscope {
+    history: [[f32; HISTORY]; 4],
+    pos: usize, //次に書き込む位置(一番古い記録)
+}
+impl Oscilloscope {
+    pub fn new() -> Self {
+        Self {
+            history: [[0.0; HISTORY]; 4],
+            pos: 0,
+        }
+    }
+
+    //各チャンネルのDACの出力(-1.0~1.0)を記録する
+    pub fn push(&mut self, outputs: [f32; 4]) {
+        for (history, out) in self.history.iter_mut().zip(outputs) {
+            history[self.pos] = out;
+        }
+        self.pos = (self.pos + 1) % HISTORY;
+    }
+
+    //古い順にi番目の記録
+    fn get(&self, ch: usize, i: usize) -> f32 {
+        self.history[ch][(self.pos + i) % HISTORY]
+    }
+
+    //波形が止まって見えるように、振幅の中央を上向きに横切る位置から描き始める
+    fn trigger(&self, ch: usize) -> usize {
+        let (min, max) = (0..HISTORY).fold((f32::MAX, f32::MIN), |(min, max), i| {
+            let val = self.get(ch, i);
+            (min.min(val), max.max(val))
+        });
+        let mid = (min + max) / 2.0;
+        let span = HISTORY - LCD_WIDTH * STEP;
+        (1..span)
+            .find(|&i| self.get(ch, i - 1) <= mid && self.get(ch, i) > mid)
+            .unwrap_or(span)
+    }
+
+    //上からチャンネル1~4の波形をLCDと同じ形式(画素ごとの明るさ)で描く
+    //聞こえていないチャンネルは薄く描く
+    pub fn render(&self, audible: [bool; 4]) -> Vec<u8> {
+        let mut pixels = vec![0xFF; LCD_PIXELS];
+        for (ch, &audible) in audible.iter().enumerate() {
+            let top = ch * LANE_HEIGHT;
+            let color = if audible { 0x00 } else { 0xAA };
+            //中央線とチャンネルの区切り
+            for x in 0..LCD_WIDTH {
+                pixels[(top + LANE_HEIGHT / 2) * LCD_WIDTH + x] = 0xAA;
+                if ch > 0 {
+                    pixels[top * LCD_WIDTH + x] = 0x55;
+                }
+            }
+            let start = self.trigger(ch);
+            let y = |val: f32| {
+                let y = ((1.0 - val.clamp(-1.0, 1.0)) / 2.0 * (LANE_HEIGHT - 3) as f32).round() as usize;
+                top + 1 + y
+            };
+            let mut prev = y(self.get(ch, start));
+            for x in 0..LCD_WIDTH {
+                let cur = y(self.get(ch, start + x * STEP));
+                //前の点と縦につないで途切れないようにする
+                for py in prev.min(cur)..=prev.max(cur) {
+                    pixels[py * LCD_WIDTH + x] = color;
+                }
+                prev = cur;
+            }
+        }
+        pixels
+    }
+}
+
+#[cfg(test)]
+mod unit_test {
+    use super::{Oscilloscope, HISTORY, LANE_HEIGHT};
+    use crate::ppu::{LCD_WIDTH, LCD_PIXELS};
+
+    #[test]
+    fn test_render() {
+        let mut scope = Oscilloscope::new();
+        for i in 0..HISTORY {
+            //チャンネル1だけ矩形波
+            let square = if i / 32 % 2 == 0 { 1.0 } else { -1.0 };
+            scope.push([square, 0.0, 0.0, 0.0]);
+        }
+        let pixels = scope.render([true, true, false, true]);
+        assert_eq!(LCD_PIXELS, pixels.len());
+        let column = |x: usize, top: usize| -> Vec<u8> {
+            (top..top + LANE_HEIGHT).map(|y| pixels[y * LCD_WIDTH + x]).collect()
+        };
+        //矩形波は上端か下端に描かれる
+        let lane = column(3, 0);
+        assert!(lane[1] == 0x00 || lane[LANE_HEIGHT - 2] == 0x00);
+        //無音のチャンネルは中央に描かれ、聞こえないチャンネルは薄い
+        assert_eq!(0x00, column(3, LANE_HEIGHT)[LANE_HEIGHT / 2]);
+        assert_eq!(0xAA, column(3, LANE_HEIGHT * 2)[LANE_HEIGHT / 2]);
+    }
+}
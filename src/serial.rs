@@ -0,0 +1,209 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::interruputs::{self, Interrupts};
+
+//SC Register
+const TRANSFER_START: u8 = 1 << 7;
+const INTERNAL_CLOCK: u8 = 1 << 0;
+
+//内部クロック8192Hz 1bitあたり128マシンサイクル
+const BIT_CYCLES: u16 = 128;
+
+//通信ケーブルの向こう側
+pub trait LinkPort {
+    //内部クロックで転送開始したときに呼ばれる 送信バイトを受け取って受信バイトを返す
+    fn exchange(&mut self, out: u8) -> u8;
+    //外部クロック待ちの間毎サイクル呼ばれる 相手がクロックを供給したら受信バイトを返す
+    fn external_clock(&mut self, _out: u8) -> Option<u8> {
+        None
+    }
+}
+
+//何も繋がっていない 0xFFが入ってくる
+pub struct Disconnected;
+impl LinkPort for Disconnected {
+    fn exchange(&mut self, _out: u8) -> u8 {
+        0xFF
+    }
+}
+
+//送信されたバイトをバッファに貯める テストROMの出力確認用
+#[derive(Default)]
+pub struct CaptureLink(Rc<RefCell<Vec<u8>>>);
+impl CaptureLink {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    //LinkPortとして渡した後も中身を見れるようにバッファを共有する
+    pub fn buffer(&self) -> Rc<RefCell<Vec<u8>>> {
+        self.0.clone()
+    }
+}
+impl LinkPort for CaptureLink {
+    fn exchange(&mut self, out: u8) -> u8 {
+        self.0.borrow_mut().push(out);
+        0xFF
+    }
+}
+
+//送信したバイトがそのまま返ってくる
+pub struct Loopback;
+impl LinkPort for Loopback {
+    fn exchange(&mut self, out: u8) -> u8 {
+        out
+    }
+    fn external_clock(&mut self, out: u8) -> Option<u8> {
+        Some(out)
+    }
+}
+
+pub struct Serial {
+    sb: u8, //0xFF01
+    sc: u8, //0xFF02
+    link: Box<dyn LinkPort>,
+    incoming: u8, //相手から受け取って未だシフトインしていないbit
+    bits: u8, //残りの転送bit数
+    cycles: u16,
+}
+impl Serial {
+    pub fn new() -> Self {
+        Self {
+            sb: 0,
+            sc: 0,
+            link: Box::new(Disconnected),
+            incoming: 0xFF,
+            bits: 0,
+            cycles: 0,
+        }
+    }
+
+    pub fn set_link(&mut self, link: Box<dyn LinkPort>) {
+        self.link = link;
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF01 => self.sb,
+            0xFF02 => 0x7E | self.sc,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            0xFF01 => self.sb = val,
+            0xFF02 => {
+                self.sc = val & (TRANSFER_START | INTERNAL_CLOCK);
+                if self.sc == TRANSFER_START | INTERNAL_CLOCK {
+                    self.incoming = self.link.exchange(self.sb);
+                    self.bits = 8;
+                    self.cycles = BIT_CYCLES;
+                }
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn emulate_cycle(&mut self, interrupts: &mut Interrupts) {
+        if self.sc & TRANSFER_START == 0 {
+            return;
+        }
+        //外部クロックは相手次第 供給されなければずっと待つ
+        if self.sc & INTERNAL_CLOCK == 0 {
+            if let Some(incoming) = self.link.external_clock(self.sb) {
+                self.sb = incoming;
+                self.finish(interrupts);
+            }
+            return;
+        }
+        self.cycles -= 1;
+        if self.cycles > 0 {
+            return;
+        }
+        self.sb = (self.sb << 1) | (self.incoming >> 7);
+        self.incoming <<= 1;
+        self.bits -= 1;
+        if self.bits == 0 {
+            self.finish(interrupts);
+        } else {
+            self.cycles = BIT_CYCLES;
+        }
+    }
+
+    fn finish(&mut self, interrupts: &mut Interrupts) {
+        self.sc &= !TRANSFER_START;
+        interrupts.irq(interruputs::SERIAL);
+    }
+}
+impl Default for Serial {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod unit_test {
+    use super::{CaptureLink, Loopback, Serial, BIT_CYCLES};
+    use crate::interruputs::{self, Interrupts};
+
+    #[test]
+    fn test_disconnected() {
+        let mut serial = Serial::new();
+        let mut interrupts = Interrupts::default();
+        serial.write(0xFF01, 0x42);
+        serial.write(0xFF02, 0x81);
+        for _ in 0..BIT_CYCLES * 8 - 1 {
+            serial.emulate_cycle(&mut interrupts);
+        }
+        assert_eq!(0xFF, serial.read(0xFF02));
+        serial.emulate_cycle(&mut interrupts);
+        assert_eq!(0xFF, serial.read(0xFF01));
+        assert_eq!(0x7F, serial.read(0xFF02));
+        assert!(interrupts.int_flags & interruputs::SERIAL > 0);
+    }
+
+    #[test]
+    fn test_capture() {
+        let mut serial = Serial::new();
+        let link = CaptureLink::new();
+        let buffer = link.buffer();
+        serial.set_link(Box::new(link));
+        serial.write(0xFF01, b'P');
+        serial.write(0xFF02, 0x81);
+        assert_eq!(b"P", buffer.borrow().as_slice());
+    }
+
+    #[test]
+    fn test_external_clock() {
+        let mut serial = Serial::new();
+        let mut interrupts = Interrupts::default();
+        serial.write(0xFF01, 0x42);
+        serial.write(0xFF02, 0x80);
+        for _ in 0..BIT_CYCLES * 16 {
+            serial.emulate_cycle(&mut interrupts);
+        }
+        //クロックが来ないので終わらない
+        assert_eq!(0xFE, serial.read(0xFF02));
+        serial.set_link(Box::new(Loopback));
+        serial.emulate_cycle(&mut interrupts);
+        assert_eq!(0x42, serial.read(0xFF01));
+        assert!(interrupts.int_flags & interruputs::SERIAL > 0);
+    }
+
+    #[test]
+    fn test_loopback() {
+        let mut serial = Serial::new();
+        let mut interrupts = Interrupts::default();
+        serial.set_link(Box::new(Loopback));
+        serial.write(0xFF01, 0xA5);
+        serial.write(0xFF02, 0x81);
+        for _ in 0..BIT_CYCLES * 8 {
+            serial.emulate_cycle(&mut interrupts);
+        }
+        //送ったバイトがそのまま戻ってくる
+        assert_eq!(0xA5, serial.read(0xFF01));
+        assert_eq!(0x7F, serial.read(0xFF02));
+        assert!(interrupts.int_flags & interruputs::SERIAL > 0);
+    }
+}
@@ -0,0 +1,175 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+
+use crate::bootrom::Bootrom;
+use crate::cartridge::Cartridge;
+use crate::gameboy::{GameBoy, FRAME_CYCLES};
+use crate::serial::CaptureLink;
+
+//blargg: 0xA001~0xA003にこの値が書かれていたら0xA000が結果コード
+const BLARGG_SIGNATURE: [u8; 3] = [0xDE, 0xB0, 0x61];
+const BLARGG_RUNNING: u8 = 0x80;
+
+//mooneye: LD B,B実行時にB,C,D,E,H,Lがフィボナッチ数なら成功、全部0x42なら失敗
+const MOONEYE_PASS: [u8; 6] = [3, 5, 8, 13, 21, 34];
+const MOONEYE_FAIL: [u8; 6] = [0x42; 6];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum TestResult {
+    Passed,
+    Failed(String),
+    Timeout,
+}
+
+//SDL無しでテストROMを実行して結果を判定する
+pub struct TestRunner {
+    gb: GameBoy,
+    serial: Rc<RefCell<Vec<u8>>>,
+    cycles: u64,
+}
+impl TestRunner {
+    pub fn new(bootrom: Bootrom, cartridge: Cartridge) -> Self {
+        let mut gb = GameBoy::new(bootrom, cartridge);
+        let link = CaptureLink::new();
+        let serial = link.buffer();
+        gb.set_link_port(Box::new(link));
+        Self {
+            gb,
+            serial,
+            cycles: 0,
+        }
+    }
+
+    //max_cyclesマシンサイクルまで実行する
+    pub fn run(&mut self, max_cycles: u64) -> TestResult {
+        while self.cycles < max_cycles {
+            self.gb.emulate_cycle();
+            self.cycles += 1;
+
+            if self.gb.cpu.is_ld_b_b()
+                && let Some(result) = self.check_mooneye()
+            {
+                return result;
+            }
+            if self.cycles.is_multiple_of(FRAME_CYCLES as u64)
+                && let Some(result) = self.check_serial().or_else(|| self.check_blargg_sram())
+            {
+                return result;
+            }
+        }
+        TestResult::Timeout
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn serial_output(&self) -> String {
+        String::from_utf8_lossy(&self.serial.borrow()).into_owned()
+    }
+
+    fn check_mooneye(&self) -> Option<TestResult> {
+        let regs = &self.gb.cpu.regs;
+        let vals = [regs.b, regs.c, regs.d, regs.e, regs.h, regs.l];
+        if vals == MOONEYE_PASS {
+            Some(TestResult::Passed)
+        } else if vals == MOONEYE_FAIL {
+            Some(TestResult::Failed("mooneye failure registers".to_string()))
+        } else {
+            None
+        }
+    }
+
+    fn check_serial(&self) -> Option<TestResult> {
+        let output = self.serial_output();
+        if output.contains("Passed") {
+            Some(TestResult::Passed)
+        } else if output.contains("Failed") {
+            Some(TestResult::Failed(output.trim().to_string()))
+        } else {
+            None
+        }
+    }
+
+    fn check_blargg_sram(&self) -> Option<TestResult> {
+        let read = |addr: u16| self.gb.peripherals.read(&self.gb.cpu.interrupts, addr);
+        if [read(0xA001), read(0xA002), read(0xA003)] != BLARGG_SIGNATURE {
+            return None;
+        }
+        match read(0xA000) {
+            BLARGG_RUNNING => None,
+            0x00 => Some(TestResult::Passed),
+            code => {
+                //0xA004からヌル終端の文字列
+                let text = (0xA004..0xC000)
+                    .map(read)
+                    .take_while(|&c| c != 0)
+                    .collect::<Vec<u8>>();
+                Some(TestResult::Failed(format!(
+                    "result code {:02X}: {}",
+                    code,
+                    String::from_utf8_lossy(&text).trim()
+                )))
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod unit_test {
+    use super::{TestResult, TestRunner};
+    use crate::bootrom::Bootrom;
+    use crate::cartridge::Cartridge;
+    use crate::gameboy::FRAME_CYCLES;
+    use crate::header::unit_test::fix_header;
+
+    //0x150からprogramを置いた32KiBのROM 0x100からはNOP; JP 0x0150
+    fn rom(program: &[u8]) -> Box<[u8]> {
+        let mut rom = vec![0; 0x8000];
+        rom[0x100..0x104].copy_from_slice(&[0x00, 0xC3, 0x50, 0x01]);
+        rom[0x150..0x150 + program.len()].copy_from_slice(program);
+        fix_header(&mut rom);
+        rom.into_boxed_slice()
+    }
+
+    #[test]
+    fn test_mooneye_pass() {
+        let program = [
+            0x06, 3, 0x0E, 5, 0x16, 8, 0x1E, 13, 0x26, 21, 0x2E, 34, //LD B~L, n
+            0x40, //LD B,B
+            0x18, 0xFE, //JR -2
+        ];
+        let mut runner = TestRunner::new(Bootrom::disabled(), Cartridge::new(rom(&program)).unwrap());
+        assert_eq!(TestResult::Passed, runner.run(1000));
+    }
+
+    #[test]
+    fn test_mooneye_fail() {
+        let program = [
+            0x06, 0x42, 0x0E, 0x42, 0x16, 0x42, 0x1E, 0x42, 0x26, 0x42, 0x2E, 0x42,
+            0x40,
+            0x18, 0xFE,
+        ];
+        let mut runner = TestRunner::new(Bootrom::disabled(), Cartridge::new(rom(&program)).unwrap());
+        assert!(matches!(runner.run(1000), TestResult::Failed(_)));
+    }
+
+    #[test]
+    fn test_serial_passed() {
+        let mut runner = TestRunner::new(Bootrom::disabled(), Cartridge::new(rom(&[0x18, 0xFE])).unwrap());
+        for &c in b"Passed" {
+            runner.gb.peripherals.write(&mut runner.gb.cpu.interrupts, 0xFF01, c);
+            runner.gb.peripherals.write(&mut runner.gb.cpu.interrupts, 0xFF02, 0x81);
+        }
+        assert_eq!(TestResult::Passed, runner.run(FRAME_CYCLES as u64));
+        assert_eq!("Passed", runner.serial_output());
+    }
+
+    #[test]
+    fn test_timeout_without_sram() {
+        //SRAMの無いカートリッジでも毎フレームの判定で落ちない
+        let mut runner = TestRunner::new(Bootrom::disabled(), Cartridge::new(rom(&[0x18, 0xFE])).unwrap());
+        assert_eq!(TestResult::Timeout, runner.run(FRAME_CYCLES as u64 * 2));
+        assert_eq!(FRAME_CYCLES as u64 * 2, runner.cycles());
+    }
+}
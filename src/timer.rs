@@ -0,0 +1,172 @@
+use crate::interruputs::{self, Interrupts};
+
+//TAC Register
+const TIMER_ENABLE: u8 = 1 << 2;
+const CLOCK_SELECT: u8 = 0b11;
+
+/*
+DIVは内部16bitカウンタの上位8bit
+TIMAはTACで選んだカウンタのbitの立ち下がりでインクリメントされる
+TAC  | 周波数    | bit
+0b00 | 4096Hz   | 9
+0b01 | 262144Hz | 3
+0b10 | 65536Hz  | 5
+0b11 | 16384Hz  | 7
+*/
+const CLOCK_BITS: [u16; 4] = [1 << 9, 1 << 3, 1 << 5, 1 << 7];
+
+#[derive(Default)]
+pub struct Timer {
+    div: u16, //0xFF04(上位8bit)
+    tima: u8, //0xFF05
+    tma: u8, //0xFF06
+    tac: u8, //0xFF07
+    overflow: bool, //TIMAがオーバーフローしてTMAのリロード待ち(この間TIMAは0を返す)
+    reloading: bool, //TMAをリロードしたサイクル
+}
+impl Timer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn read(&self, addr: u16) -> u8 {
+        match addr {
+            0xFF04 => (self.div >> 8) as u8,
+            0xFF05 => self.tima,
+            0xFF06 => self.tma,
+            0xFF07 => 0xF8 | self.tac,
+            _ => unreachable!(),
+        }
+    }
+
+    pub fn write(&mut self, addr: u16, val: u8) {
+        match addr {
+            //DIVへの書き込みはカウンタごと0にするので立ち下がりが起きうる
+            0xFF04 => {
+                let old = self.signal();
+                self.div = 0;
+                self.detect_falling_edge(old);
+            },
+            //リロード待ちの間に書き込むとリロードはキャンセル、リロードしたサイクルの書き込みは無視される
+            0xFF05 => if !self.reloading {
+                self.tima = val;
+                self.overflow = false;
+            },
+            //リロードしたサイクルにTMAを書き込むとTIMAにも反映される
+            0xFF06 => {
+                self.tma = val;
+                if self.reloading {
+                    self.tima = val;
+                }
+            },
+            //DMGではTACの書き換えでも立ち下がりが起きる
+            0xFF07 => {
+                let old = self.signal();
+                self.tac = val & 0b111;
+                self.detect_falling_edge(old);
+            },
+            _ => unreachable!(),
+        }
+    }
+
+    //DIVの内部カウンタ(APUのフレームシーケンサが使う)
+    pub fn div_counter(&self) -> u16 {
+        self.div
+    }
+
+    //ブートROMを飛ばしたときに、実行し終えた時点のカウンタから始める
+    pub fn set_div_counter(&mut self, val: u16) {
+        self.div = val;
+    }
+
+    fn signal(&self) -> bool {
+        self.tac & TIMER_ENABLE > 0 && self.div & CLOCK_BITS[(self.tac & CLOCK_SELECT) as usize] > 0
+    }
+
+    fn detect_falling_edge(&mut self, old: bool) {
+        if old && !self.signal() {
+            let (tima, overflow) = self.tima.overflowing_add(1);
+            self.tima = tima;
+            self.overflow = overflow;
+        }
+    }
+
+    //1マシンサイクル(4クロック)分進める
+    pub fn emulate_cycle(&mut self, interrupts: &mut Interrupts) {
+        self.reloading = false;
+        if self.overflow {
+            self.overflow = false;
+            self.reloading = true;
+            self.tima = self.tma;
+            interrupts.irq(interruputs::TIMER);
+        }
+        let old = self.signal();
+        self.div = self.div.wrapping_add(4);
+        self.detect_falling_edge(old);
+    }
+}
+
+#[cfg(test)]
+mod unit_test {
+    use super::Timer;
+    use crate::interruputs::{self, Interrupts};
+
+    #[test]
+    fn test_div() {
+        let mut timer = Timer::new();
+        let mut interrupts = Interrupts::default();
+        for _ in 0..64 {
+            timer.emulate_cycle(&mut interrupts);
+        }
+        assert_eq!(1, timer.read(0xFF04));
+        timer.write(0xFF04, 0x42);
+        assert_eq!(0, timer.read(0xFF04));
+    }
+
+    #[test]
+    fn test_tima_reload() {
+        let mut timer = Timer::new();
+        let mut interrupts = Interrupts::default();
+        timer.write(0xFF06, 0x80);
+        timer.write(0xFF05, 0xFF);
+        timer.write(0xFF07, 0b101);
+        for _ in 0..4 {
+            timer.emulate_cycle(&mut interrupts);
+        }
+        //オーバーフロー直後の1サイクルは0のまま
+        assert_eq!(0x00, timer.read(0xFF05));
+        assert_eq!(0, interrupts.int_flags & interruputs::TIMER);
+        timer.emulate_cycle(&mut interrupts);
+        assert_eq!(0x80, timer.read(0xFF05));
+        assert!(interrupts.int_flags & interruputs::TIMER > 0);
+    }
+
+    #[test]
+    fn test_tima_write_cancels_reload() {
+        let mut timer = Timer::new();
+        let mut interrupts = Interrupts::default();
+        timer.write(0xFF06, 0x80);
+        timer.write(0xFF05, 0xFF);
+        timer.write(0xFF07, 0b101);
+        for _ in 0..4 {
+            timer.emulate_cycle(&mut interrupts);
+        }
+        timer.write(0xFF05, 0x10);
+        timer.emulate_cycle(&mut interrupts);
+        assert_eq!(0x10, timer.read(0xFF05));
+        assert_eq!(0, interrupts.int_flags & interruputs::TIMER);
+    }
+
+    #[test]
+    fn test_div_reset_falling_edge() {
+        let mut timer = Timer::new();
+        let mut interrupts = Interrupts::default();
+        timer.write(0xFF07, 0b101);
+        for _ in 0..2 {
+            timer.emulate_cycle(&mut interrupts);
+        }
+        //bit3が立っている状態でDIVをリセットするとTIMAが進む
+        timer.write(0xFF04, 0);
+        assert_eq!(1, timer.read(0xFF05));
+    }
+}
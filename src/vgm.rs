@@ -0,0 +1,109 @@
+use std::io::{self, Write};
+
+use crate::gameboy::{CPU_CLOCK_HZ, M_CYCLE_CLOCK};
+
+//VGMの時間の単位
+const VGM_RATE: u64 = 44100;
+const M_CYCLES_PER_SEC: u64 = (CPU_CLOCK_HZ / M_CYCLE_CLOCK) as u64;
+//Game Boy DMGの指定があるのは1.61以降
+const VERSION: u32 = 0x171;
+const HEADER_SIZE: usize = 0x100;
+
+//VGMのコマンド
+const CMD_DMG_WRITE: u8 = 0xB3; //0xB3 aa dd: 0xFF10 + aaにddを書き込む
+const CMD_WAIT: u8 = 0x61; //0x61 nn nn: nサンプル待つ
+const CMD_WAIT_60HZ: u8 = 0x62; //735サンプル
+const CMD_WAIT_50HZ: u8 = 0x63; //882サンプル
+const CMD_WAIT_SHORT: u8 = 0x70; //0x7n: n+1サンプル
+const CMD_END: u8 = 0x66;
+
+//APUのレジスタへの書き込みをVGM形式で記録する
+//ヘッダに全体の長さが要るので、コマンドは溜めておいてfinishでまとめて書き出す
+pub struct VgmWriter<W: Write> {
+    out: W,
+    commands: Vec<u8>,
+    start: u64, //記録を始めたマシンサイクル
+    samples: u64, //ここまでに書いた待ちの合計
+}
+impl<W: Write> VgmWriter<W> {
+    pub fn new(out: W, start: u64) -> Self {
+        Self {
+            out,
+            commands: vec![],
+            start,
+            samples: 0,
+        }
+    }
+
+    //cycleマシンサイクル目にaddr(0xFF10~0xFF3F)へvalを書き込んだ
+    pub fn write_register(&mut self, cycle: u64, addr: u16, val: u8) {
+        self.wait_until(cycle);
+        self.commands.extend_from_slice(&[CMD_DMG_WRITE, (addr - 0xFF10) as u8, val]);
+    }
+
+    fn wait_until(&mut self, cycle: u64) {
+        let target = (cycle - self.start) * VGM_RATE / M_CYCLES_PER_SEC;
+        let mut wait = target - self.samples;
+        self.samples = target;
+        while wait > 0 {
+            let n = wait.min(0xFFFF);
+            match n {
+                1..=16 => self.commands.push(CMD_WAIT_SHORT + (n - 1) as u8),
+                735 => self.commands.push(CMD_WAIT_60HZ),
+                882 => self.commands.push(CMD_WAIT_50HZ),
+                _ => {
+                    self.commands.push(CMD_WAIT);
+                    self.commands.extend_from_slice(&(n as u16).to_le_bytes());
+                },
+            }
+            wait -= n;
+        }
+    }
+
+    //cycleマシンサイクル目で記録を終えて書き出す
+    pub fn finish(mut self, cycle: u64) -> io::Result<W> {
+        self.wait_until(cycle);
+        self.commands.push(CMD_END);
+        let mut header = vec![0; HEADER_SIZE];
+        let mut put = |offset: usize, val: u32| header[offset..offset + 4].copy_from_slice(&val.to_le_bytes());
+        put(0x04, (HEADER_SIZE + self.commands.len() - 4) as u32); //EOFまでの相対位置
+        put(0x08, VERSION);
+        put(0x18, self.samples as u32); //全体のサンプル数
+        put(0x34, (HEADER_SIZE - 0x34) as u32); //データまでの相対位置
+        put(0x80, CPU_CLOCK_HZ as u32); //DMGのクロック
+        header[0x00..0x04].copy_from_slice(b"Vgm ");
+        self.out.write_all(&header)?;
+        self.out.write_all(&self.commands)?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+#[cfg(test)]
+mod unit_test {
+    use super::{VgmWriter, HEADER_SIZE, M_CYCLES_PER_SEC};
+
+    #[test]
+    fn test_commands() {
+        let mut vgm = VgmWriter::new(vec![], 100);
+        vgm.write_register(100, 0xFF26, 0x80);
+        //1/60秒後
+        vgm.write_register(100 + M_CYCLES_PER_SEC / 60 + 1, 0xFF24, 0x77);
+        vgm.write_register(100 + M_CYCLES_PER_SEC / 60 + 200, 0xFF30, 0x12);
+        let data = vgm.finish(100 + M_CYCLES_PER_SEC).unwrap();
+        assert_eq!(b"Vgm ", &data[0..4]);
+        assert_eq!((data.len() - 4) as u32, u32::from_le_bytes(data[0x04..0x08].try_into().unwrap()));
+        assert_eq!(44100, u32::from_le_bytes(data[0x18..0x1C].try_into().unwrap()));
+        assert_eq!(4194304, u32::from_le_bytes(data[0x80..0x84].try_into().unwrap()));
+        assert_eq!(
+            vec![
+                0xB3, 0x16, 0x80,
+                0x62, 0xB3, 0x14, 0x77,
+                0x77, 0xB3, 0x20, 0x12,
+                0x61, 0x5D, 0xA9,
+                0x66,
+            ],
+            data[HEADER_SIZE..]
+        );
+    }
+}
@@ -0,0 +1,93 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const HEADER_SIZE: u32 = 44;
+
+//ステレオ16bit PCMのWAVファイルに書き出す
+//サイズは最後まで分からないので、finishで先頭のヘッダを書き直す
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    sample_rate: u32,
+    frames: u32, //書き込んだフレーム(L, Rの組)の数
+}
+impl WavWriter<BufWriter<File>> {
+    pub fn create(path: impl AsRef<Path>, sample_rate: u32) -> io::Result<Self> {
+        Self::new(BufWriter::new(File::create(path)?), sample_rate)
+    }
+}
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut out: W, sample_rate: u32) -> io::Result<Self> {
+        out.write_all(&header(sample_rate, 0))?;
+        Ok(Self {
+            out,
+            sample_rate,
+            frames: 0,
+        })
+    }
+
+    //samplesはL, Rの順 -1.0~1.0の範囲外は切り詰める
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        let mut data = Vec::with_capacity(samples.len() * 2);
+        for &sample in samples {
+            let val = (sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16;
+            data.extend_from_slice(&val.to_le_bytes());
+        }
+        self.out.write_all(&data)?;
+        self.frames += samples.len() as u32 / 2;
+        Ok(())
+    }
+
+    //ヘッダのサイズを埋めて書き出しを終える
+    pub fn finish(mut self) -> io::Result<W> {
+        self.out.seek(SeekFrom::Start(0))?;
+        self.out.write_all(&header(self.sample_rate, self.frames))?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+fn header(sample_rate: u32, frames: u32) -> Vec<u8> {
+    let channels = 2u16;
+    let bits = 16u16;
+    let block_align = channels * bits / 8;
+    let data_size = frames * block_align as u32;
+    let mut data = Vec::with_capacity(HEADER_SIZE as usize);
+    data.extend_from_slice(b"RIFF");
+    data.extend_from_slice(&(HEADER_SIZE - 8 + data_size).to_le_bytes());
+    data.extend_from_slice(b"WAVE");
+    //fmtチャンク
+    data.extend_from_slice(b"fmt ");
+    data.extend_from_slice(&16u32.to_le_bytes());
+    data.extend_from_slice(&1u16.to_le_bytes()); //PCM
+    data.extend_from_slice(&channels.to_le_bytes());
+    data.extend_from_slice(&sample_rate.to_le_bytes());
+    data.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    data.extend_from_slice(&block_align.to_le_bytes());
+    data.extend_from_slice(&bits.to_le_bytes());
+    //dataチャンク
+    data.extend_from_slice(b"data");
+    data.extend_from_slice(&data_size.to_le_bytes());
+    data
+}
+
+#[cfg(test)]
+mod unit_test {
+    use std::io::Cursor;
+    use super::WavWriter;
+
+    #[test]
+    fn test_write() {
+        let mut wav = WavWriter::new(Cursor::new(vec![]), 48000).unwrap();
+        wav.write_samples(&[0.0, 1.0, -1.0, 2.0]).unwrap();
+        let data = wav.finish().unwrap().into_inner();
+        assert_eq!(44 + 8, data.len());
+        assert_eq!(b"RIFF", &data[0..4]);
+        assert_eq!(44u32, u32::from_le_bytes(data[4..8].try_into().unwrap()));
+        assert_eq!(48000u32, u32::from_le_bytes(data[24..28].try_into().unwrap()));
+        assert_eq!(8u32, u32::from_le_bytes(data[40..44].try_into().unwrap()));
+        let samples: Vec<i16> = data[44..].chunks(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect();
+        assert_eq!(vec![0, 32767, -32767, 32767], samples);
+    }
+}